@@ -3,6 +3,9 @@ local M = {
     return true
   end,
 
+  -- Questions are asked to get a fresh answer, never a cached one.
+  cache = false,
+
   action_name = function()
     return "Answer below"
   end,
@@ -5,6 +5,54 @@ local function findup(node, kind)
   return node
 end
 
+local function same_range(a, b)
+  local ra, rb = a:range(), b:range()
+  return ra.start_line == rb.start_line and ra.start_character == rb.start_character
+end
+
+-- Attribute names like `obj.n` and keyword names like `f(n=1)` are not the variable.
+local function is_variable(id)
+  local parent = id:parent()
+  if parent == nil then
+    return true
+  end
+  local field = ({ attribute = "attribute", keyword_argument = "name" })[parent:kind()]
+  if field == nil then
+    return true
+  end
+  local named = parent:child_by_field_name(field)
+  return named == nil or not same_range(named, id)
+end
+
+-- The function around the identifier, or the whole module outside of one.
+local function scope_of(node)
+  return findup(node, "function_definition") or active_doc:root()
+end
+
+-- The text of `scope` with every use of `old_name` renamed to `new_name`.
+local function rename_in(scope, old_name, new_name)
+  local scope_range = scope:range()
+  local lines = {}
+  for line in (active_doc:text_from_node(scope) .. "\n"):gmatch("(.-)\n") do
+    table.insert(lines, line)
+  end
+  local ids = active_doc:query(scope, "(identifier) @id")
+  -- Back to front, so the columns of the earlier ones stay valid.
+  for i = #ids, 1, -1 do
+    local id = ids[i]
+    if active_doc:text_from_node(id) == old_name and is_variable(id) then
+      local r = id:range()
+      local row = r.start_line - scope_range.start_line + 1
+      local offset = row == 1 and scope_range.start_character or 0
+      local line = lines[row]
+      lines[row] = line:sub(1, r.start_character - offset)
+        .. new_name
+        .. line:sub(r.end_character - offset + 1)
+    end
+  end
+  return table.concat(lines, "\n")
+end
+
 local M = {
   is_triggered = function(lsp_range)
     local start_node = active_doc:node_from_range(lsp_range)
@@ -18,8 +66,22 @@ local M = {
     return "List Identifier Name Options"
   end,
 
+  -- The model lists several names, each is offered as a rename within the
+  -- function around the identifier.
+  candidates = 5,
+  candidate_source = "numbered_list",
+
+  candidate_title = function(name)
+    return "Rename to `" .. name:gsub("`", "") .. "`"
+  end,
+
   process_answer = function(llm_response, lsp_range)
-    return llm_response
+    local new_name = llm_response:gsub("`", ""):gsub("^%s+", ""):gsub("%s+$", "")
+    if not new_name:match("^[%a_][%w_]*$") then
+      return nil
+    end
+    local id = findup(active_doc:node_from_range(lsp_range), "identifier")
+    return rename_in(scope_of(id), active_doc:text_from_node(id), new_name)
   end,
 
   create_prompt = function(lsp_range)
@@ -29,8 +91,7 @@ local M = {
     end
     local var_node = findup(start_node, "identifier")
     local var_text = active_doc:text_from_node(var_node)
-    local func_node = findup(start_node, "function_definition")
-    local function_text = active_doc:text_from_node(func_node)
+    local function_text = active_doc:text_from_node(scope_of(var_node))
 
     return table.concat({
       [=====[ Human:
@@ -41,18 +102,15 @@ local M = {
 ]=====], function_text, [=====[
       </context>
 
-      List me 20 Options of how I can name that identifer given the context.
+      List me 5 options of how I can name that identifier given the context, as
+      a numbered list like "1. name". Only write the names, no explanations.
 Assistant: ]=====] })
   end,
 
   placement_range = function(lsp_range)
-    -- place below
-    return {
-      start_line = lsp_range.end_line + 1,
-      start_character = 0,
-      end_line = lsp_range.end_line + 1,
-      end_character = 0,
-    }
+    -- replace the function, renaming every use of the identifier
+    local start_node = active_doc:node_from_range(lsp_range)
+    return scope_of(findup(start_node, "identifier")):range()
   end
 }
 
@@ -0,0 +1,325 @@
+//! Implementation of the `apply` subcommand, which runs a single code action on
+//! a file without an editor, and of the `prompt` subcommand, which shows what
+//! the action would ask the model.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use tower_lsp::lsp_types::{
+    CodeAction, DocumentChangeOperation, DocumentChanges, OneOf, Position, Range, ResourceOp, Url,
+};
+
+use crate::code_action_providers::helper;
+use crate::code_action_providers::parsed_document::ParsedDocument;
+use crate::code_action_providers::traits::{ActionProvider, PromptPreview};
+use crate::code_action_providers::{collect_code_actions, find_resolver, is_named, ProviderMap};
+use crate::server::{nop_codeaction, ResolveActionKind};
+
+/// Selects a code action and where in which file to run it.
+#[derive(clap::Args)]
+pub struct TargetArgs {
+    /// The file to run the code action on
+    file: PathBuf,
+
+    /// Name of the code action, e.g. "Update Function Docstring"
+    #[arg(long)]
+    action: String,
+
+    /// Cursor position as 1-based LINE:COL
+    #[arg(long, value_parser = parse_position, required_unless_present = "range", conflicts_with = "range")]
+    cursor: Option<Position>,
+
+    /// Selection as 1-based LINE:COL-LINE:COL
+    #[arg(long, value_parser = parse_range)]
+    range: Option<Range>,
+
+    /// Language id of the file, guessed from the file extension if omitted
+    #[arg(long)]
+    language: Option<String>,
+}
+
+#[derive(clap::Args)]
+pub struct ApplyArgs {
+    #[command(flatten)]
+    target: TargetArgs,
+
+    /// Print a unified diff instead of the edited file
+    #[arg(long)]
+    diff: bool,
+
+    /// Which of several candidate answers to apply, starting at 1
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    candidate: u32,
+}
+
+/// Parses a 1-based `LINE:COL` into a 0-based position.
+///
+/// Columns are byte offsets into the line, as reported by most editors.
+pub fn parse_position(s: &str) -> Result<Position, String> {
+    let (line, col) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected LINE:COL, got `{s}`"))?;
+    let line: u32 = line.parse().map_err(|_| format!("invalid line `{line}`"))?;
+    let col: u32 = col.parse().map_err(|_| format!("invalid column `{col}`"))?;
+    if line == 0 || col == 0 {
+        return Err("lines and columns start at 1".to_owned());
+    }
+    Ok(Position::new(line - 1, col - 1))
+}
+
+/// Parses a 1-based `LINE:COL-LINE:COL` into a 0-based range.
+pub fn parse_range(s: &str) -> Result<Range, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected LINE:COL-LINE:COL, got `{s}`"))?;
+    Ok(Range::new(parse_position(start)?, parse_position(end)?))
+}
+
+/// Guesses the language id from the file extension.
+pub fn language_from_path(path: &Path) -> Option<&'static str> {
+    match path.extension()?.to_str()? {
+        "py" => Some("python"),
+        "rs" => Some("rust"),
+        "go" => Some("go"),
+        "md" => Some("markdown"),
+        "txt" => Some("text"),
+        _ => None,
+    }
+}
+
+/// Applies all edits of a resolved code action for `uri` to `source`.
+pub fn apply_edits(source: &str, uri: &Url, action: &CodeAction) -> String {
+    let mut edits = action
+        .edit
+        .as_ref()
+        .and_then(|edit| edit.changes.as_ref())
+        .and_then(|changes| changes.get(uri))
+        .cloned()
+        .unwrap_or_default();
+    // Apply from the bottom up so earlier edits do not shift later ranges.
+    edits
+        .sort_by_key(|edit| std::cmp::Reverse((edit.range.start.line, edit.range.start.character)));
+
+    let mut text = source.to_owned();
+    for edit in edits {
+        text = helper::apply_text_edit(&text, &edit);
+    }
+    text
+}
+
+/// The files a resolved code action creates, with their content.
+pub fn created_files(action: &CodeAction) -> Vec<(Url, String)> {
+    let Some(DocumentChanges::Operations(operations)) = action
+        .edit
+        .as_ref()
+        .and_then(|edit| edit.document_changes.as_ref())
+    else {
+        return Vec::new();
+    };
+    let mut files: Vec<(Url, String)> = Vec::new();
+    for operation in operations {
+        match operation {
+            DocumentChangeOperation::Op(ResourceOp::Create(create)) => {
+                files.push((create.uri.clone(), String::new()));
+            }
+            DocumentChangeOperation::Edit(edit) => {
+                let uri = &edit.text_document.uri;
+                if let Some((_, content)) = files.iter_mut().find(|(file, _)| file == uri) {
+                    for edit in edit.edits.iter() {
+                        if let OneOf::Left(edit) = edit {
+                            content.push_str(&edit.new_text);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    files
+}
+
+/// Finds the code action `action_name` offered at `range` and the provider
+/// resolving it.
+fn find_action<'a>(
+    providers: &'a ProviderMap,
+    doc: &ParsedDocument,
+    lang: &str,
+    action_name: &str,
+    range: &Range,
+) -> anyhow::Result<(CodeAction, &'a dyn ActionProvider)> {
+    let actions = collect_code_actions(providers, doc, lang, range, &[]);
+    let Some(action) = actions.iter().find(|action| is_named(action, action_name)) else {
+        let available: Vec<&str> = actions.iter().map(|a| a.title.as_str()).collect();
+        bail!(
+            "action `{action_name}` is not available here, available actions: {}",
+            available.join(", ")
+        );
+    };
+
+    let args: ResolveActionKind = serde_json::from_value(
+        action
+            .data
+            .clone()
+            .ok_or_else(|| anyhow!("action has no data"))?,
+    )?;
+    let provider = find_resolver(providers, &args.id, lang)
+        .ok_or_else(|| anyhow!("no provider can resolve `{}`", args.id))?;
+    Ok((action.clone(), provider.as_ref()))
+}
+
+/// Runs the code action `action_name` at `range` and returns the resolved
+/// action of every candidate answer.
+///
+/// This is the same pipeline the language server runs for `textDocument/codeAction`
+/// followed by `codeAction/resolve`. The range is in UTF8 byte columns.
+pub async fn resolve_action(
+    providers: &ProviderMap,
+    source: &str,
+    uri: &Url,
+    lang: &str,
+    action_name: &str,
+    range: &Range,
+) -> anyhow::Result<Vec<CodeAction>> {
+    let doc = ParsedDocument::new(source, uri, lang);
+    let (action, provider) = find_action(providers, &doc, lang, action_name, range)?;
+    provider
+        .on_resolve_candidates(&doc, action)
+        .await
+        .map_err(|e| anyhow!("cannot resolve action: {e}"))
+}
+
+/// Runs the code action `action_name` at `range` and returns the source edited
+/// by the first candidate.
+pub async fn apply_action(
+    providers: &ProviderMap,
+    source: &str,
+    uri: &Url,
+    lang: &str,
+    action_name: &str,
+    range: &Range,
+) -> anyhow::Result<String> {
+    let resolved = resolve_action(providers, source, uri, lang, action_name, range).await?;
+    Ok(match resolved.first() {
+        Some(resolved) => apply_edits(source, uri, resolved),
+        None => source.to_owned(),
+    })
+}
+
+/// Shows what the code action `action_name` at `range` would ask the model,
+/// without asking it.
+pub fn preview_action(
+    providers: &ProviderMap,
+    source: &str,
+    uri: &Url,
+    lang: &str,
+    action_name: &str,
+    range: &Range,
+) -> anyhow::Result<PromptPreview> {
+    let doc = ParsedDocument::new(source, uri, lang);
+    let (action, provider) = find_action(providers, &doc, lang, action_name, range)?;
+    provider
+        .preview(&doc, &action)
+        .map_err(|e| anyhow!("cannot build prompt: {e}"))
+}
+
+/// Renders a unified diff between the original and the edited file.
+pub fn unified_diff(path: &Path, original: &str, edited: &str) -> String {
+    let name = path.display().to_string();
+    similar::TextDiff::from_lines(original, edited)
+        .unified_diff()
+        .header(&name, &name)
+        .to_string()
+}
+
+/// Reads the file of `args` and returns its source, uri, language and the
+/// selected range.
+fn read_target(args: &TargetArgs) -> anyhow::Result<(String, Url, String, Range)> {
+    let source = fs::read_to_string(&args.file)?;
+    let lang = match &args.language {
+        Some(lang) => lang.clone(),
+        None => language_from_path(&args.file)
+            .ok_or_else(|| {
+                anyhow!(
+                    "cannot guess language of {}, use --language",
+                    args.file.display()
+                )
+            })?
+            .to_owned(),
+    };
+    let uri = Url::from_file_path(fs::canonicalize(&args.file)?)
+        .map_err(|_| anyhow!("invalid path {}", args.file.display()))?;
+    let range = match (args.range, args.cursor) {
+        (Some(range), _) => range,
+        (None, Some(cursor)) => Range::new(cursor, cursor),
+        (None, None) => bail!("either --cursor or --range is required"),
+    };
+    Ok((source, uri, lang, range))
+}
+
+/// Runs the `apply` subcommand and prints the result to stdout.
+pub async fn run(args: ApplyArgs, providers: &ProviderMap) -> anyhow::Result<()> {
+    let (source, uri, lang, range) = read_target(&args.target)?;
+    let candidates =
+        resolve_action(providers, &source, &uri, &lang, &args.target.action, &range).await?;
+    if candidates.len() > 1 {
+        for (i, candidate) in candidates.iter().enumerate() {
+            eprintln!("candidate {}: {}", i + 1, candidate.title);
+        }
+    }
+    let resolved = match candidates.get(args.candidate as usize - 1) {
+        Some(resolved) => resolved.clone(),
+        None if candidates.is_empty() => nop_codeaction(),
+        None => bail!("there are only {} candidates", candidates.len()),
+    };
+    let edited = apply_edits(&source, &uri, &resolved);
+    let created = created_files(&resolved);
+    if args.diff {
+        print!("{}", unified_diff(&args.target.file, &source, &edited));
+        for (file, content) in created.iter() {
+            let path = file.to_file_path().unwrap_or_else(|_| file.path().into());
+            print!("{}", unified_diff(&path, "", content));
+        }
+    } else if created.is_empty() {
+        print!("{edited}");
+    } else {
+        // The file itself is unchanged, show what would be created instead.
+        for (file, content) in created.iter() {
+            eprintln!("would create {file}");
+            print!("{content}");
+        }
+    }
+    Ok(())
+}
+
+/// Formats a range as 1-based `LINE:COL-LINE:COL`, the inverse of `parse_range`.
+fn format_range(range: &Range) -> String {
+    format!(
+        "{}:{}-{}:{}",
+        range.start.line + 1,
+        range.start.character + 1,
+        range.end.line + 1,
+        range.end.character + 1
+    )
+}
+
+/// Runs the `prompt` subcommand and prints the prompt to stdout.
+pub fn run_prompt(args: TargetArgs, providers: &ProviderMap) -> anyhow::Result<()> {
+    let (source, uri, lang, range) = read_target(&args)?;
+    let preview = preview_action(providers, &source, &uri, &lang, &args.action, &range)?;
+    match &preview.context_node {
+        Some(node) => println!(
+            "context node:    {} {}",
+            node.kind,
+            format_range(&node.range)
+        ),
+        None => println!("context node:    (none)"),
+    }
+    match &preview.placement_range {
+        Some(range) => println!("placement range: {}", format_range(range)),
+        None => println!("placement range: (none, the answer would be discarded)"),
+    }
+    println!();
+    println!("{}", preview.prompt);
+    Ok(())
+}
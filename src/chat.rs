@@ -0,0 +1,218 @@
+//! Implementation of the `chat` subcommand, a multi-turn conversation with the
+//! configured model on the command line.
+
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail};
+
+use crate::configuration::ModelConfig;
+use crate::llm_handlers::resilient::ResilientLLM;
+use crate::llm_handlers::traits::Llm;
+
+const HELP: &str = "\
+/reset         start a new conversation
+/model [ID]    show the model or switch to model ID
+/file PATH     add the content of PATH to the next message
+/save PATH     save the transcript as markdown
+/help          show this help
+/quit          leave the chat (or press Ctrl-D)";
+
+#[derive(clap::Args)]
+pub struct ChatArgs {
+    /// Save the transcript as markdown to this file when the chat ends
+    #[arg(long)]
+    transcript: Option<PathBuf>,
+}
+
+struct Turn {
+    question: String,
+    answer: String,
+}
+
+struct Chat {
+    prompt_handler: Arc<Llm>,
+    model: ModelConfig,
+    history: Vec<Turn>,
+    attachments: Vec<String>,
+    transcript: String,
+}
+
+impl Chat {
+    fn new(prompt_handler: Arc<Llm>, model: ModelConfig) -> Self {
+        Chat {
+            prompt_handler,
+            model,
+            history: Vec::new(),
+            attachments: Vec::new(),
+            transcript: String::new(),
+        }
+    }
+
+    fn model_name(&self) -> String {
+        self.prompt_handler.model_id()
+    }
+
+    /// Renders the whole conversation followed by `question` as a single prompt.
+    fn build_prompt(&self, question: &str) -> String {
+        let mut prompt = String::new();
+        for turn in self.history.iter() {
+            prompt.push_str(&format!(
+                "\n\nHuman: {}\n\nAssistant: {}",
+                turn.question, turn.answer
+            ));
+        }
+        prompt.push_str(&format!("\n\nHuman: {question}\n\nAssistant:"));
+        prompt
+    }
+
+    async fn ask(&mut self, message: &str) -> anyhow::Result<String> {
+        let mut question = self.attachments.join("\n");
+        if !question.is_empty() {
+            question.push('\n');
+        }
+        question.push_str(message);
+
+        if let Some(usage) = self.prompt_handler.usage() {
+            usage.check_budget()?;
+        }
+        let answer = self
+            .prompt_handler
+            .uncached()
+            .answer_with_usage(&self.build_prompt(&question))
+            .await?;
+        if let Some(usage) = self.prompt_handler.usage() {
+            usage.record("chat", &answer.model, answer.usage);
+        }
+        let answer = answer.text.trim().to_owned();
+        self.attachments.clear();
+        self.transcript.push_str(&format!(
+            "## User\n\n{question}\n\n## Assistant\n\n{answer}\n\n"
+        ));
+        self.history.push(Turn {
+            question,
+            answer: answer.clone(),
+        });
+        Ok(answer)
+    }
+
+    fn reset(&mut self) {
+        self.history.clear();
+        self.attachments.clear();
+        if !self.transcript.is_empty() {
+            self.transcript.push_str("---\n\n");
+        }
+    }
+
+    async fn switch_model(&mut self, model_id: &str) -> anyhow::Result<()> {
+        if !self.prompt_handler.is_bedrock() {
+            bail!(
+                "cannot switch the model of the {} backend",
+                self.model_name()
+            );
+        }
+        let mut model = self.model.clone();
+        model.bedrock.model_id = model_id.to_owned();
+        self.prompt_handler = self.prompt_handler.with_backend(Arc::new(Llm::Resilient(
+            ResilientLLM::from_config(&model).await?,
+        )));
+        self.model = model;
+        Ok(())
+    }
+
+    fn attach_file(&mut self, path: &Path) -> anyhow::Result<usize> {
+        let content =
+            fs::read_to_string(path).map_err(|e| anyhow!("cannot read {}: {e}", path.display()))?;
+        let num_lines = content.lines().count();
+        self.attachments.push(format!(
+            "<file path=\"{}\">\n{}\n</file>",
+            path.display(),
+            content.trim_end()
+        ));
+        Ok(num_lines)
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let content = format!("# Chat with {}\n\n{}", self.model_name(), self.transcript);
+        fs::write(path, content.trim_end().to_owned() + "\n")
+            .map_err(|e| anyhow!("cannot write {}: {e}", path.display()))
+    }
+
+    /// Runs a `/` command, returns `false` if the chat should end.
+    async fn run_command(&mut self, line: &str) -> bool {
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let arg = arg.trim();
+        let result = match command {
+            "/reset" => {
+                self.reset();
+                Ok("started a new conversation".to_owned())
+            }
+            "/model" if arg.is_empty() => Ok(self.model_name()),
+            "/model" => self
+                .switch_model(arg)
+                .await
+                .map(|_| format!("switched to {arg}")),
+            "/file" if arg.is_empty() => Err(anyhow!("usage: /file PATH")),
+            "/file" => self
+                .attach_file(Path::new(arg))
+                .map(|num_lines| format!("added {arg} ({num_lines} lines) to the next message")),
+            "/save" if arg.is_empty() => Err(anyhow!("usage: /save PATH")),
+            "/save" => self
+                .save(Path::new(arg))
+                .map(|_| format!("saved transcript to {arg}")),
+            "/help" => Ok(HELP.to_owned()),
+            "/quit" | "/exit" => return false,
+            _ => Err(anyhow!("unknown command {command}, try /help")),
+        };
+        match result {
+            Ok(message) => eprintln!("{message}"),
+            Err(e) => eprintln!("{e}"),
+        }
+        true
+    }
+}
+
+/// Runs the `chat` subcommand until `/quit` or the end of stdin.
+pub async fn run(
+    args: ChatArgs,
+    prompt_handler: Arc<Llm>,
+    model: ModelConfig,
+) -> anyhow::Result<()> {
+    let mut chat = Chat::new(prompt_handler, model);
+    eprintln!(
+        "chatting with {}, type /help for commands",
+        chat.model_name()
+    );
+
+    let stdin = io::stdin();
+    loop {
+        eprint!("> ");
+        io::stderr().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            eprintln!();
+            break;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('/') {
+            if !chat.run_command(line).await {
+                break;
+            }
+            continue;
+        }
+        match chat.ask(line).await {
+            Ok(answer) => println!("{answer}\n"),
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+
+    if let Some(path) = args.transcript {
+        chat.save(&path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,248 @@
+//! Implementation of the `check` subcommand, which validates the server config
+//! and all code actions without starting the language server.
+
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use tree_sitter::Query;
+
+use crate::code_action_providers::lua::bindings::LuaInterface;
+use crate::code_action_providers::parsed_document::{ts_language, GRAMMARS};
+use crate::code_action_providers::yaml::config::CodeActionConfig;
+use crate::code_action_providers::SUPPORTED_LANGUAGES;
+use crate::configuration::{ConfigLayout, PolyglotConfig, ProjectConfig};
+use crate::read_language_config_files;
+
+/// A single problem in a config file.
+struct Finding {
+    path: PathBuf,
+    line: Option<usize>,
+    message: String,
+}
+
+impl Finding {
+    fn new(path: &Path, line: Option<usize>, message: String) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            line,
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Returns the 1-based line where `query` starts within `lines[range]`, found by
+/// its first non-empty line.
+fn find_query_line(lines: &[&str], range: Range<usize>, query: &str) -> Option<usize> {
+    let (skipped, first) = query
+        .lines()
+        .enumerate()
+        .find(|(_, line)| !line.trim().is_empty())?;
+    let first = first.trim();
+    let end = range.end.min(lines.len());
+    (range.start.min(end)..end)
+        .find(|&i| lines[i].contains(first))
+        .map(|i| (i + 1).saturating_sub(skipped))
+}
+
+/// Returns the range of lines holding each item of the `code_actions` list, or
+/// the whole file for every action if the items cannot be told apart.
+fn action_ranges(lines: &[&str], count: usize) -> Vec<Range<usize>> {
+    let item = |line: &str| {
+        let rest = line.trim_start();
+        rest.starts_with("- ").then(|| line.len() - rest.len())
+    };
+    let starts = lines
+        .iter()
+        .position(|line| line.starts_with("code_actions:"))
+        .and_then(|list| {
+            let indent = lines[list + 1..].iter().find_map(|line| item(line))?;
+            Some(
+                (list + 1..lines.len())
+                    .filter(|&i| item(lines[i]) == Some(indent))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .filter(|starts| starts.len() == count);
+    match starts {
+        Some(starts) => starts
+            .iter()
+            .enumerate()
+            .map(|(k, &start)| start..starts.get(k + 1).copied().unwrap_or(lines.len()))
+            .collect(),
+        None => vec![0..lines.len(); count],
+    }
+}
+
+/// Checks the function queries of the review against their grammars.
+fn check_review(path: &Path, config: &PolyglotConfig, findings: &mut Vec<Finding>) {
+    let source = fs::read_to_string(path).unwrap_or_default();
+    let lines: Vec<&str> = source.lines().collect();
+    let review = lines
+        .iter()
+        .position(|line| line.starts_with("review:"))
+        .unwrap_or(0);
+    for (language, review_config) in config.review.languages.iter() {
+        let Some(query) = &review_config.query else {
+            continue;
+        };
+        let message = match ts_language(language) {
+            None => format!("review: no tree-sitter grammar for language `{language}`"),
+            Some(ts_lang) => match Query::new(&ts_lang, query) {
+                Ok(_) => continue,
+                Err(e) => format!(
+                    "review query of `{language}` is invalid ({:?}): {}",
+                    e.kind,
+                    e.message.trim()
+                ),
+            },
+        };
+        let start = (review..lines.len())
+            .find(|&i| lines[i].trim_start().starts_with(&format!("{language}:")))
+            .unwrap_or(review);
+        findings.push(Finding::new(
+            path,
+            find_query_line(&lines, start..lines.len(), query),
+            message,
+        ));
+    }
+}
+
+fn check_yaml_actions(path: &Path, language: &str, findings: &mut Vec<Finding>) {
+    let config = match CodeActionConfig::from_yaml(&path) {
+        Ok(config) => config,
+        Err(e) => {
+            findings.push(Finding::new(path, None, e.to_string()));
+            return;
+        }
+    };
+
+    // Actions for a language without a grammar run against every grammar.
+    let grammars: Vec<_> = match ts_language(language) {
+        Some(grammar) => vec![(language, grammar)],
+        None => GRAMMARS
+            .iter()
+            .filter_map(|name| Some((*name, ts_language(name)?)))
+            .collect(),
+    };
+
+    let source = fs::read_to_string(path).unwrap_or_default();
+    let lines: Vec<&str> = source.lines().collect();
+    let ranges = action_ranges(&lines, config.code_actions.len());
+    for (action, range) in config.code_actions.iter().zip(ranges) {
+        // Each problem with the grammars it was found in.
+        let mut problems: Vec<(Option<usize>, String, Vec<&str>)> = Vec::new();
+        for (name, grammar) in grammars.iter() {
+            for error in action.validate(grammar) {
+                let line = error
+                    .query
+                    .as_deref()
+                    .and_then(|query| find_query_line(&lines, range.clone(), query))
+                    .map(|line| line + error.row);
+                match problems
+                    .iter_mut()
+                    .find(|(l, message, _)| *l == line && *message == error.message)
+                {
+                    Some((_, _, names)) => names.push(name),
+                    None => problems.push((line, error.message, vec![name])),
+                }
+            }
+        }
+        for (line, message, names) in problems {
+            let message = if names.len() == grammars.len() {
+                format!("action '{}': {message}", action.name)
+            } else {
+                format!(
+                    "action '{}' in {}: {message}",
+                    action.name,
+                    names.join(", ")
+                )
+            };
+            findings.push(Finding::new(path, line, message));
+        }
+    }
+}
+
+fn check_lua_action(path: &Path, findings: &mut Vec<Finding>) {
+    let result = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|source| LuaInterface::try_new(&source).map_err(|e| e.to_string()));
+    if let Err(message) = result {
+        findings.push(Finding::new(path, None, message));
+    }
+}
+
+fn check_code_actions(code_actions_dir: &Path, findings: &mut Vec<Finding>) -> usize {
+    let mut num_files = 0;
+    for language in SUPPORTED_LANGUAGES {
+        let config_dir = code_actions_dir.join(language);
+        for path in read_language_config_files(&config_dir, "yaml") {
+            check_yaml_actions(&path, language, findings);
+            num_files += 1;
+        }
+        for path in read_language_config_files(&config_dir, "lua") {
+            check_lua_action(&path, findings);
+            num_files += 1;
+        }
+    }
+    num_files
+}
+
+/// Validates the server config, the user-level code actions and the code
+/// actions of the project in the current directory.
+///
+/// Prints every problem and returns `true` if no problems were found.
+pub fn check_configs(layout: &ConfigLayout) -> bool {
+    let mut findings = Vec::new();
+    let mut num_files = 0;
+
+    if layout.server_config.exists() {
+        num_files += 1;
+        match PolyglotConfig::try_read_from_file(&layout.server_config) {
+            Ok(config) => check_review(&layout.server_config, &config, &mut findings),
+            Err(e) => findings.push(Finding::new(&layout.server_config, None, e.to_string())),
+        }
+    }
+    num_files += check_code_actions(&layout.code_actions_dir, &mut findings);
+
+    let project_dir = PathBuf::from(ProjectConfig::DIR_NAME);
+    if project_dir.is_dir() {
+        let project_config = project_dir.join("config.yaml");
+        if project_config.exists() {
+            num_files += 1;
+            if let Err(e) = ProjectConfig::try_read_from_file(&project_config) {
+                findings.push(Finding::new(&project_config, None, e.to_string()));
+            }
+        }
+        num_files += check_code_actions(&project_dir.join("code_actions"), &mut findings);
+    }
+
+    for finding in findings.iter() {
+        eprintln!("{finding}");
+    }
+    if findings.is_empty() {
+        println!("checked {num_files} files, no problems found");
+    } else {
+        eprintln!(
+            "checked {num_files} files, found {} problems",
+            findings.len()
+        );
+    }
+    findings.is_empty()
+}
+
+/// Returns the JSON Schema of the YAML code action format.
+pub fn code_action_schema() -> String {
+    let schema = schemars::schema_for!(CodeActionConfig);
+    serde_json::to_string_pretty(&schema).expect("can serialize schema")
+}
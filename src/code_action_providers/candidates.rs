@@ -0,0 +1,135 @@
+//! Actions offering several answers to choose from.
+
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tower_lsp::jsonrpc::Result;
+
+use super::helper;
+use crate::llm_handlers::traits::{Answer, Llm};
+
+/// Where the candidates of an action come from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CandidateSource {
+    /// One request per candidate, all but the first bypass the response cache.
+    #[default]
+    Requests,
+    /// One request whose answer is a numbered list of candidates.
+    NumberedList,
+}
+
+impl CandidateSource {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "requests" => Some(CandidateSource::Requests),
+            "numbered_list" => Some(CandidateSource::NumberedList),
+            _ => None,
+        }
+    }
+}
+
+/// One call to the model and the candidates in its answer.
+pub struct Completion {
+    pub answer: Answer,
+    pub latency: Duration,
+    pub candidates: Vec<String>,
+}
+
+/// The items of a list like `1. foo` or `2) bar`. Lines following an item
+/// belong to it, text before the first item is dropped.
+pub fn parse_numbered_list(text: &str) -> Vec<String> {
+    static ITEM: OnceLock<Regex> = OnceLock::new();
+    let item = ITEM.get_or_init(|| Regex::new(r"^\s*\d+[.)]\s+(.*)$").expect("valid regex"));
+    let mut items: Vec<String> = Vec::new();
+    for line in text.lines() {
+        match (item.captures(line), items.last_mut()) {
+            (Some(captures), _) => items.push(captures[1].to_owned()),
+            (None, Some(last)) if !line.trim().is_empty() => {
+                last.push('\n');
+                last.push_str(line);
+            }
+            _ => {}
+        }
+    }
+    items
+}
+
+/// The title of the code action applying `candidate`, its first line.
+pub fn default_title(candidate: &str) -> String {
+    candidate
+        .trim()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+async fn ask(llm: &Llm, prompt: &str, action: &str) -> Result<(Answer, Duration)> {
+    if let Some(usage) = llm.usage() {
+        usage.check_budget().map_err(helper::internal_error)?;
+    }
+    let started = Instant::now();
+    let answer = llm
+        .answer_with_usage(prompt)
+        .await
+        .map_err(helper::internal_error)?;
+    let latency = started.elapsed();
+    if let Some(usage) = llm.usage() {
+        usage.record(action, &answer.model, answer.usage);
+    }
+    Ok((answer, latency))
+}
+
+/// Asks `llm` for `count` candidates, recording the usage for `action`.
+pub async fn complete(
+    llm: &Llm,
+    prompt: &str,
+    action: &str,
+    count: usize,
+    source: CandidateSource,
+) -> Result<Vec<Completion>> {
+    let mut completions = Vec::new();
+    match source {
+        _ if count <= 1 => {
+            let (answer, latency) = ask(llm, prompt, action).await?;
+            let candidates = vec![answer.text.clone()];
+            completions.push(Completion {
+                answer,
+                latency,
+                candidates,
+            });
+        }
+        CandidateSource::Requests => {
+            for i in 0..count {
+                // A cached answer would only repeat the first candidate.
+                let llm = if i == 0 { llm } else { llm.uncached() };
+                let (answer, latency) = ask(llm, prompt, action).await?;
+                let candidates = vec![answer.text.clone()];
+                completions.push(Completion {
+                    answer,
+                    latency,
+                    candidates,
+                });
+            }
+        }
+        CandidateSource::NumberedList => {
+            let (answer, latency) = ask(llm, prompt, action).await?;
+            let mut candidates = parse_numbered_list(&answer.text);
+            candidates.truncate(count);
+            if candidates.is_empty() {
+                log::info!("no numbered list in the answer of {action}, using all of it");
+                candidates.push(answer.text.clone());
+            }
+            completions.push(Completion {
+                answer,
+                latency,
+                candidates,
+            });
+        }
+    }
+    Ok(completions)
+}
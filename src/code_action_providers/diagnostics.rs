@@ -0,0 +1,254 @@
+//! Diagnostics reported by other language servers, and the built-in action
+//! asking the model to fix them.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::json;
+use tower_lsp::jsonrpc::{Error, Result};
+use tower_lsp::lsp_types::{
+    CodeAction, CodeActionKind, Diagnostic, DiagnosticSeverity, NumberOrString, Position, Range,
+    TextEdit, WorkspaceEdit,
+};
+
+use super::candidates::{self, CandidateSource};
+use super::helper;
+use super::parsed_document::ParsedDocument;
+use super::syntax;
+use super::traits::{ActionContext, ActionProvider, PromptPreview};
+use super::yaml::config::PostprocessStep;
+use super::yaml::{postprocess, template};
+use crate::llm_handlers::traits::Llm;
+use crate::logging::audit;
+use crate::server::ResolveAction;
+
+/// The name of the built-in quickfix.
+pub const ACTION_NAME: &str = "Fix with LLM";
+const ID: &str = "__builtin__/fix_with_llm";
+/// Lines around the diagnostics the model rewrites.
+const CONTEXT_LINES: usize = 5;
+/// Lines around the rewritten ones shown to the model for reference.
+const REFERENCE_LINES: usize = 20;
+
+/// The name of `severity` as used in prompts.
+pub fn severity_name(severity: Option<DiagnosticSeverity>) -> &'static str {
+    match severity {
+        Some(DiagnosticSeverity::ERROR) => "error",
+        Some(DiagnosticSeverity::WARNING) => "warning",
+        Some(DiagnosticSeverity::INFORMATION) => "information",
+        Some(DiagnosticSeverity::HINT) => "hint",
+        _ => "problem",
+    }
+}
+
+/// The code of a diagnostic, like `E0308`.
+pub fn code_text(code: &NumberOrString) -> String {
+    match code {
+        NumberOrString::Number(number) => number.to_string(),
+        NumberOrString::String(code) => code.clone(),
+    }
+}
+
+/// One line per diagnostic, like `line 3: error[E0308] (rustc): mismatched types`.
+pub fn describe(diagnostics: &[Diagnostic]) -> String {
+    let lines: Vec<String> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let code = diagnostic
+                .code
+                .as_ref()
+                .map(|code| format!("[{}]", code_text(code)))
+                .unwrap_or_default();
+            let source = diagnostic
+                .source
+                .as_ref()
+                .map(|source| format!(" ({source})"))
+                .unwrap_or_default();
+            format!(
+                "line {}: {}{code}{source}: {}",
+                diagnostic.range.start.line + 1,
+                severity_name(diagnostic.severity),
+                diagnostic.message.trim()
+            )
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Offers to fix the diagnostics at the cursor by rewriting the lines around
+/// them.
+pub struct DiagnosticFixProvider {
+    prompt_handler: Arc<Llm>,
+}
+
+impl DiagnosticFixProvider {
+    pub fn new(prompt_handler: Arc<Llm>) -> Self {
+        Self { prompt_handler }
+    }
+
+    /// The lines the model rewrites, the first and the last line of them.
+    fn region(doc: &ParsedDocument, diagnostics: &[Diagnostic]) -> (usize, usize) {
+        let line_count = doc.source().lines().count().max(1);
+        let first = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.range.start.line as usize)
+            .min()
+            .unwrap_or_default();
+        let last = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.range.end.line as usize)
+            .max()
+            .unwrap_or_default();
+        (
+            first.saturating_sub(CONTEXT_LINES).min(line_count - 1),
+            (last + CONTEXT_LINES).min(line_count - 1),
+        )
+    }
+
+    /// The range of the lines `first..=last` without the final line break.
+    fn region_range(doc: &ParsedDocument, first: usize, last: usize) -> Range {
+        let last_line = doc.source().lines().nth(last).unwrap_or_default();
+        Range::new(
+            Position::new(first as u32, 0),
+            Position::new(last as u32, last_line.len() as u32),
+        )
+    }
+
+    fn prompt(
+        doc: &ParsedDocument,
+        diagnostics: &[Diagnostic],
+        first: usize,
+        last: usize,
+    ) -> String {
+        let lines: Vec<&str> = doc.source().lines().collect();
+        let region = lines.get(first..=last).unwrap_or_default().join("\n");
+        let (before, after) = template::lines_around(doc.source(), first, last, REFERENCE_LINES);
+        let language = doc.language();
+        let mut prompt = format!(
+            "Fix the following problems reported in the {language} file {}:\n\n{}\n\n",
+            doc.uri,
+            describe(diagnostics)
+        );
+        if !before.is_empty() {
+            prompt.push_str(&format!(
+                "The code before the lines to fix:\n```{language}\n{before}\n```\n\n"
+            ));
+        }
+        if !after.is_empty() {
+            prompt.push_str(&format!(
+                "The code after the lines to fix:\n```{language}\n{after}\n```\n\n"
+            ));
+        }
+        prompt.push_str(&format!(
+            "The lines {from} to {to} to fix:\n```{language}\n{region}\n```\n\n\
+             Answer with the fixed lines {from} to {to} in a single code block and nothing \
+             else. Keep everything unrelated to the problems as it is.",
+            from = first + 1,
+            to = last + 1,
+        ));
+        prompt
+    }
+}
+
+#[async_trait]
+impl ActionProvider for DiagnosticFixProvider {
+    fn action_name(&self) -> String {
+        ACTION_NAME.to_owned()
+    }
+    fn can_handle(&self, action_name: &str) -> bool {
+        action_name == ID
+    }
+    async fn on_resolve(&self, doc: &ParsedDocument, action: CodeAction) -> Result<CodeAction> {
+        let args = serde_json::from_value::<ResolveAction<ActionContext>>(
+            action.data.clone().ok_or(Error::invalid_request())?,
+        )
+        .map_err(|_| Error::invalid_request())?
+        .data;
+        if args.diagnostics.is_empty() {
+            return Err(Error::request_cancelled());
+        }
+
+        let (first, last) = Self::region(doc, &args.diagnostics);
+        let prompt = Self::prompt(doc, &args.diagnostics, first, last);
+        log::debug!("prompt of {ID}: {prompt}");
+        let completion = candidates::complete(
+            &self.prompt_handler,
+            &prompt,
+            ACTION_NAME,
+            1,
+            CandidateSource::Requests,
+        )
+        .await?
+        .pop()
+        .ok_or(Error::request_cancelled())?;
+        let new_text = postprocess::apply(
+            &[PostprocessStep::StripFences],
+            completion.answer.text.clone(),
+            doc.language(),
+            &doc.uri,
+        )
+        .map_err(|e| helper::internal_error(format!("{ACTION_NAME}: {e}")))?
+        .trim_end_matches('\n')
+        .to_owned();
+        audit(
+            ID,
+            &args.uri,
+            &prompt,
+            &completion.answer,
+            Some(&new_text),
+            completion.latency,
+        );
+
+        let text_edit = TextEdit {
+            range: Self::region_range(doc, first, last),
+            new_text,
+        };
+        syntax::check_edit(doc, &text_edit)
+            .map_err(|e| helper::internal_error(format!("{ACTION_NAME}: {e}")))?;
+        let mut action = action;
+        action.edit = Some(WorkspaceEdit {
+            changes: Some([(args.uri, vec![text_edit])].into_iter().collect()),
+            ..Default::default()
+        });
+        Ok(action)
+    }
+    fn preview(&self, doc: &ParsedDocument, action: &CodeAction) -> Result<PromptPreview> {
+        let args = serde_json::from_value::<ResolveAction<ActionContext>>(
+            action.data.clone().ok_or(Error::invalid_request())?,
+        )
+        .map_err(|_| Error::invalid_request())?
+        .data;
+        let (first, last) = Self::region(doc, &args.diagnostics);
+        Ok(PromptPreview {
+            action: ACTION_NAME.to_owned(),
+            prompt: Self::prompt(doc, &args.diagnostics, first, last),
+            placement_range: Some(Self::region_range(doc, first, last)),
+            context_node: None,
+        })
+    }
+    fn create_code_action(
+        &self,
+        doc: &ParsedDocument,
+        start_range: &Range,
+        diagnostics: &[Diagnostic],
+    ) -> Option<CodeAction> {
+        if diagnostics.is_empty() {
+            return None;
+        }
+        Some(CodeAction {
+            title: format!("Polyglot: {ACTION_NAME}"),
+            kind: Some(CodeActionKind::QUICKFIX),
+            diagnostics: Some(diagnostics.to_vec()),
+            data: Some(json!(ResolveAction {
+                id: ID.to_owned(),
+                data: ActionContext {
+                    uri: doc.uri.to_owned(),
+                    selection_range: start_range.to_owned(),
+                    selection: None,
+                    diagnostics: diagnostics.to_vec(),
+                }
+            })),
+            ..Default::default()
+        })
+    }
+}
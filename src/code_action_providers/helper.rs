@@ -1,6 +1,8 @@
-use tower_lsp::lsp_types::{Position, Range};
+use tower_lsp::lsp_types::{Position, Range, TextEdit};
 use tree_sitter::Point;
 
+use crate::nonsense::{self, IndexedText, TextChange};
+
 // Convert Tree-sitter Point to LSP Position
 pub fn ts_point_to_lsp_position(point: &Point) -> Position {
     Position {
@@ -57,7 +59,11 @@ pub fn findup<'a>(
 }
 
 pub fn indent_text(text: &str, indent_amount: usize) -> String {
-    let indent = " ".repeat(indent_amount);
+    prefix_lines(text, &" ".repeat(indent_amount))
+}
+
+/// Puts `indent` in front of every line of `text`.
+pub fn prefix_lines(text: &str, indent: &str) -> String {
     trim_last_newline(
         &text
             .lines()
@@ -73,3 +79,24 @@ fn trim_last_newline(input: &str) -> String {
     }
     result
 }
+
+/// Turns an error of the model or of a script into a JSON-RPC error the editor
+/// can show.
+pub fn internal_error(message: impl ToString) -> tower_lsp::jsonrpc::Error {
+    let mut error = tower_lsp::jsonrpc::Error::internal_error();
+    error.message = message.to_string().into();
+    error
+}
+
+/// Applies `edit`, whose range is in UTF8 byte columns, to `source`.
+pub fn apply_text_edit(source: &str, edit: &TextEdit) -> String {
+    let index = IndexedText::new(source);
+    let change = TextChange {
+        range: Some(
+            nonsense::Pos::new(edit.range.start.line, edit.range.start.character)
+                ..nonsense::Pos::new(edit.range.end.line, edit.range.end.character),
+        ),
+        patch: edit.new_text.clone(),
+    };
+    nonsense::apply_change(&index, change)
+}
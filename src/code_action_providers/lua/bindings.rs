@@ -2,7 +2,7 @@ use std::ffi::CStr;
 
 use mlua::{FromLua, Function, Lua, Result, Table, UserData, UserDataMethods, Value};
 use serde::{Deserialize, Serialize};
-use tower_lsp::lsp_types::{Position, Range};
+use tower_lsp::lsp_types::{Diagnostic, Position, Range};
 use tree_sitter::{
     ffi::{
         ts_node_child, ts_node_child_by_field_name, ts_node_child_count, ts_node_is_null,
@@ -12,6 +12,9 @@ use tree_sitter::{
     Node,
 };
 
+use crate::code_action_providers::candidates::{default_title, CandidateSource};
+use crate::code_action_providers::diagnostics::{code_text, severity_name};
+use crate::code_action_providers::syntax::SyntaxCheck;
 use crate::code_action_providers::{helper::ts_node_to_lsp_range, parsed_document::ParsedDocument};
 
 #[derive(Copy, Clone, Debug)]
@@ -265,31 +268,55 @@ struct LuaImpl {
     create_prompt: Function,
     placement_range: Function,
     process_answer: Option<Function>,
+    /// Whether answers may come from the response cache.
+    cache: bool,
+    /// What happens to an answer whose edit adds syntax errors.
+    validate_syntax: SyntaxCheck,
+    /// How many answers to offer to choose from.
+    candidates: usize,
+    candidate_source: CandidateSource,
+    candidate_title: Option<Function>,
 }
 
 impl FromLua for LuaImpl {
     fn from_lua(value: Value, lua: &Lua) -> Result<Self> {
         let table: Table = lua.unpack(value)?;
-        let action_name: String = table
-            .get::<_, Function>("action_name")
-            .expect("find action_name")
-            .call(())
-            .unwrap();
+        let action_name: String = table.get::<_, Function>("action_name")?.call(())?;
 
-        let is_triggered = table.get("is_triggered").expect("find is_triggered");
-        let create_prompt = table
-            .get("create_prompt")
-            .expect("create_prompt is_triggered");
-        let placement_range = table
-            .get("placement_range")
-            .expect("findplacement_rangeis_triggered");
-        let process_answer = table.get("process_answer").expect("find process_answer");
+        let is_triggered = table.get("is_triggered")?;
+        let create_prompt = table.get("create_prompt")?;
+        let placement_range = table.get("placement_range")?;
+        let process_answer = table.get("process_answer")?;
+        let cache = table.get::<_, Option<bool>>("cache")?.unwrap_or(true);
+        let validate_syntax = match table.get::<_, Option<String>>("validate_syntax")? {
+            Some(name) => SyntaxCheck::from_name(&name).ok_or_else(|| {
+                mlua::Error::RuntimeError(format!(
+                    "validate_syntax must be \"off\", \"reject\" or \"retry\", got \"{name}\""
+                ))
+            })?,
+            None => SyntaxCheck::Off,
+        };
+        let candidates = table.get::<_, Option<usize>>("candidates")?.unwrap_or(1);
+        let candidate_source = match table.get::<_, Option<String>>("candidate_source")? {
+            Some(name) => CandidateSource::from_name(&name).ok_or_else(|| {
+                mlua::Error::RuntimeError(format!(
+                    "candidate_source must be \"requests\" or \"numbered_list\", got \"{name}\""
+                ))
+            })?,
+            None => CandidateSource::Requests,
+        };
+        let candidate_title = table.get("candidate_title")?;
         Ok(LuaImpl {
             action_name,
             is_triggered,
             create_prompt,
             placement_range,
             process_answer,
+            cache,
+            validate_syntax,
+            candidates,
+            candidate_source,
+            candidate_title,
             // lua,
         })
     }
@@ -307,9 +334,24 @@ pub struct LuaInterface {
 
 impl LuaInterface {
     pub fn new(lua_code: &str) -> Self {
+        Self::try_new(lua_code).expect("can load lua code")
+    }
+
+    /// Evaluates the script and checks that it returns a complete action table.
+    pub fn try_new(lua_code: &str) -> Result<Self> {
         let lua = Lua::new();
-        let value: LuaImpl = lua.load(lua_code).eval().unwrap();
-        Self { m: value, lua: lua }
+        // stdout carries the LSP messages in `--stdio` mode and the edited file
+        // in `apply`, so `print` writes to stderr instead.
+        lua.load(
+            r##"print = function(...)
+              local parts = {}
+              for i = 1, select("#", ...) do parts[i] = tostring(select(i, ...)) end
+              io.stderr:write(table.concat(parts, "\t"), "\n")
+            end"##,
+        )
+        .exec()?;
+        let value: LuaImpl = lua.load(lua_code).eval()?;
+        Ok(Self { m: value, lua })
     }
     pub fn set_doc(&self, active_doc: ParsedDocument) {
         let active_doc = LuaDoc(active_doc);
@@ -350,6 +392,28 @@ impl LuaInterface {
         self.lua.globals().set("helper", helper_table).unwrap();
     }
 
+    /// Exposes `diagnostics` to the script as the global `diagnostics`, a list
+    /// of tables with `range`, `severity`, `code`, `source` and `message`.
+    pub fn set_diagnostics(&self, diagnostics: &[Diagnostic]) {
+        let list = self.lua.create_table().unwrap();
+        for diagnostic in diagnostics {
+            let entry = self.lua.create_table().unwrap();
+            entry
+                .set("range", LuaRange::from(diagnostic.range))
+                .unwrap();
+            entry
+                .set("severity", severity_name(diagnostic.severity))
+                .unwrap();
+            entry
+                .set("code", diagnostic.code.as_ref().map(code_text))
+                .unwrap();
+            entry.set("source", diagnostic.source.clone()).unwrap();
+            entry.set("message", diagnostic.message.clone()).unwrap();
+            list.push(entry).unwrap();
+        }
+        self.lua.globals().set("diagnostics", list).unwrap();
+    }
+
     pub fn is_triggered(&self, selection_range: &Range) -> bool {
         let selection_range: LuaRange = selection_range.to_owned().into();
         self.m
@@ -368,6 +432,24 @@ impl LuaInterface {
     pub fn action_name(&self) -> String {
         self.m.action_name.clone()
     }
+    pub fn cache(&self) -> bool {
+        self.m.cache
+    }
+    pub fn validate_syntax(&self) -> SyntaxCheck {
+        self.m.validate_syntax
+    }
+    pub fn candidates(&self) -> (usize, CandidateSource) {
+        (self.m.candidates, self.m.candidate_source)
+    }
+    /// The title of the action applying the candidate `answer`.
+    pub fn candidate_title(&self, answer: &str) -> String {
+        match self.m.candidate_title.as_ref() {
+            Some(f) => f
+                .call(answer.to_string())
+                .expect("can get result from lua function candidate_title"),
+            None => default_title(answer),
+        }
+    }
     pub fn process_answer(&self, text: &str, selection_range: &Range) -> Option<String> {
         let selection_range: LuaRange = selection_range.to_owned().into();
         match self.m.process_answer.as_ref() {
@@ -386,8 +468,6 @@ impl LuaInterface {
             .call(selection_range)
             .expect("can get result from lua function placement_range");
 
-        let h: Option<Range> = placement_range.map(|n| n.into());
-        //log::info!("Place at {:?}", placement_range);
-        h
+        placement_range.map(|n| n.into())
     }
 }
@@ -5,12 +5,16 @@ use async_trait::async_trait;
 use serde_json::json;
 use thiserror::Error;
 use tower_lsp::jsonrpc::{Error, Result};
-use tower_lsp::lsp_types::{CodeAction, CodeActionKind, TextEdit, WorkspaceEdit};
+use tower_lsp::lsp_types::{CodeAction, CodeActionKind, Diagnostic, TextEdit, WorkspaceEdit};
 
+use crate::code_action_providers::candidates::CandidateSource;
 use crate::code_action_providers::parsed_document::ParsedDocument;
+use crate::code_action_providers::syntax::{self, CandidateRequest, SyntaxCheck};
 use crate::code_action_providers::traits::ActionContext;
 use crate::code_action_providers::traits::ActionProvider;
+use crate::code_action_providers::traits::PromptPreview;
 use crate::llm_handlers::traits::Llm;
+use crate::logging::audit;
 use crate::server::ResolveAction;
 
 use super::bindings::LuaInterface;
@@ -19,6 +23,10 @@ pub struct LuaProvider {
     prompt_handler: Arc<Llm>,
     lua_source: String,
     id: String,
+    action_name: String,
+    cache: bool,
+    validate_syntax: SyntaxCheck,
+    candidates: (usize, CandidateSource),
 }
 
 #[derive(Debug, Error)]
@@ -34,25 +42,48 @@ impl LuaProvider {
         file_name: &str,
         prompt_handler: Arc<Llm>,
     ) -> anyhow::Result<Self, LuaProviderError> {
+        let lua_source = fs::read_to_string(file_name)?;
+        let lua = LuaInterface::try_new(&lua_source)
+            .map_err(|e| LuaProviderError::LuaParseError(e.to_string()))?;
         Ok(Self {
             prompt_handler,
             id: file_name.to_owned(),
-            lua_source: fs::read_to_string(file_name)?,
+            lua_source,
+            action_name: lua.action_name(),
+            cache: lua.cache(),
+            validate_syntax: lua.validate_syntax(),
+            candidates: lua.candidates(),
         })
     }
 
-    pub fn create_lua_interface(&self, doc: &ParsedDocument) -> LuaInterface {
+    pub fn create_lua_interface(
+        &self,
+        doc: &ParsedDocument,
+        diagnostics: &[Diagnostic],
+    ) -> LuaInterface {
         let lua = LuaInterface::new(&self.lua_source);
         lua.set_doc(doc.duplicate());
+        lua.set_diagnostics(diagnostics);
         lua
     }
 }
 #[async_trait]
 impl ActionProvider for LuaProvider {
+    fn action_name(&self) -> String {
+        self.action_name.clone()
+    }
     fn can_handle(&self, action_name: &str) -> bool {
         action_name == self.id
     }
     async fn on_resolve(&self, doc: &ParsedDocument, action: CodeAction) -> Result<CodeAction> {
+        let actions = self.on_resolve_candidates(doc, action).await?;
+        actions.into_iter().next().ok_or(Error::request_cancelled())
+    }
+    async fn on_resolve_candidates(
+        &self,
+        doc: &ParsedDocument,
+        action: CodeAction,
+    ) -> Result<Vec<CodeAction>> {
         let args = serde_json::from_value::<ResolveAction<ActionContext>>(
             action.data.clone().expect("action data is correct"),
         )
@@ -62,7 +93,7 @@ impl ActionProvider for LuaProvider {
         let prompt;
         let range;
         {
-            let lua = self.create_lua_interface(doc);
+            let lua = self.create_lua_interface(doc, &args.diagnostics);
             range = lua
                 .placement_range(&args.selection_range)
                 .ok_or(Error::request_cancelled())?;
@@ -70,48 +101,92 @@ impl ActionProvider for LuaProvider {
                 .build_prompt(&args.selection_range)
                 .ok_or(Error::request_cancelled())?;
         }
-        //log::info!("prompt {}", prompt);
-        //log::info!("range {:?}", range);
-        let new_text = self.prompt_handler.answer(&prompt).await;
-        if new_text.is_err() {
+        log::debug!("placement of {}: {:?}", self.id, range);
+        let prompt_handler = if self.cache {
+            &self.prompt_handler
+        } else {
+            self.prompt_handler.uncached()
+        };
+        let (count, source) = self.candidates;
+        let request = CandidateRequest {
+            llm: prompt_handler,
+            action: &self.action_name,
+            id: &self.id,
+            count,
+            source,
+            check: self.validate_syntax,
+        };
+        let actions =
+            syntax::resolve_candidates(request, doc, &prompt, |prompt, completion, candidate| {
+                let (new_text, title) = {
+                    let lua = self.create_lua_interface(doc, &args.diagnostics);
+                    (
+                        lua.process_answer(candidate, &args.selection_range),
+                        lua.candidate_title(candidate),
+                    )
+                };
+                audit(
+                    &self.id,
+                    &args.uri,
+                    prompt,
+                    &completion.answer,
+                    new_text.as_deref(),
+                    completion.latency,
+                );
+                let Some(new_text) = new_text else {
+                    return Ok(None);
+                };
+                let mut action = action.clone();
+                if count > 1 {
+                    action.title = title;
+                }
+                action.edit = Some(WorkspaceEdit {
+                    changes: Some(
+                        [(args.uri.clone(), vec![TextEdit { range, new_text }])]
+                            .into_iter()
+                            .collect(),
+                    ),
+                    ..Default::default()
+                });
+                Ok(Some(action))
+            })
+            .await?;
+        if actions.is_empty() {
             return Err(Error::request_cancelled());
         }
-        let mut new_text = new_text.unwrap();
-        {
-            //log::info!("answer {}", new_text);
-            let lua = self.create_lua_interface(doc);
-            new_text = lua
-                .process_answer(&new_text, &args.selection_range)
-                .ok_or(Error::request_cancelled())?;
-            //log::info!("processed answer {}", new_text);
-        }
-        let text_edit = TextEdit { range, new_text };
-        let mut action = action.clone();
-        action.edit = Some(WorkspaceEdit {
-            changes: Some([(args.uri.clone(), vec![text_edit])].into_iter().collect()),
-            ..Default::default()
-        });
+        Ok(actions)
+    }
+    fn preview(&self, doc: &ParsedDocument, action: &CodeAction) -> Result<PromptPreview> {
+        let args = serde_json::from_value::<ResolveAction<ActionContext>>(
+            action.data.clone().ok_or(Error::invalid_request())?,
+        )
+        .map_err(|_| Error::invalid_request())?
+        .data;
 
-        return Ok(action);
+        let lua = self.create_lua_interface(doc, &args.diagnostics);
+        let prompt = lua
+            .build_prompt(&args.selection_range)
+            .ok_or(Error::request_cancelled())?;
+        Ok(PromptPreview {
+            action: self.action_name.clone(),
+            prompt,
+            placement_range: lua.placement_range(&args.selection_range),
+            // The Lua script picks its context itself.
+            context_node: None,
+        })
     }
     fn create_code_action(
         &self,
         active_doc: &ParsedDocument,
         selection_range: &tower_lsp::lsp_types::Range,
+        diagnostics: &[Diagnostic],
     ) -> Option<tower_lsp::lsp_types::CodeAction> {
-        let lua = self.create_lua_interface(active_doc);
+        let lua = self.create_lua_interface(active_doc, diagnostics);
         let is_triggered = lua.is_triggered(selection_range);
-        //log::info!("selection_range {:?}", selection_range);
-        let node = active_doc.get_ts_node_for_range(selection_range);
-        //log::info!("node {:?}", node);
-        if node.is_some() {
-            //log::info!("node text {:?}", active_doc.text_from_node(&node.unwrap()));
-        }
-        //log::info!("text {:?}", active_doc.text_from_range(selection_range));
         if !is_triggered {
             return None;
         }
-        let lua = self.create_lua_interface(active_doc);
+        let lua = self.create_lua_interface(active_doc, diagnostics);
 
         Some(CodeAction {
             title: format!("Polyglot: {}", lua.action_name()),
@@ -120,7 +195,9 @@ impl ActionProvider for LuaProvider {
                 id: self.id.to_string(),
                 data: ActionContext {
                     uri: active_doc.uri.to_owned(),
-                    selection_range: selection_range.to_owned()
+                    selection_range: selection_range.to_owned(),
+                    selection: None,
+                    diagnostics: diagnostics.to_vec(),
                 }
             })),
             ..Default::default()
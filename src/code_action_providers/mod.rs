@@ -1,7 +1,10 @@
 use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
+use diagnostics::DiagnosticFixProvider;
 use lua::provider::LuaProvider;
-use tower_lsp::lsp_types::CodeAction;
+use parsed_document::ParsedDocument;
+use thiserror::Error;
+use tower_lsp::lsp_types::{CodeAction, Diagnostic, Position, Range};
 use traits::ActionProvider;
 use yaml::{config, provider::YamlProvider};
 
@@ -11,13 +14,16 @@ use crate::{
     read_language_config_files,
 };
 
+pub mod candidates;
+pub mod diagnostics;
 pub mod helper;
 pub mod lua;
 pub mod parsed_document;
+pub mod syntax;
 pub mod traits;
 pub mod yaml;
 
-const SUPPORTED_LANGUAGES: [&str; 7] = [
+pub const SUPPORTED_LANGUAGES: [&str; 7] = [
     "gitcommit",
     "go",
     "markdown",
@@ -27,52 +33,160 @@ const SUPPORTED_LANGUAGES: [&str; 7] = [
     "__all__",
 ];
 
+/// All registered action providers, keyed by language.
+pub type ProviderMap = HashMap<String, Vec<Box<dyn ActionProvider>>>;
+
+/// A code action config file that could not be turned into a provider.
+#[derive(Debug, Error)]
+#[error("{}: {message}", path.display())]
+pub struct LoadError {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+/// Loads all YAML and Lua code actions below `code_actions_config_dir`.
+///
+/// Files that fail to load are skipped and reported in the returned error list
+/// so the caller can surface them to the user.
 pub fn load_providers(
     code_actions_config_dir: PathBuf,
     prompt_handler: Arc<Llm>,
-) -> HashMap<String, Vec<Box<dyn ActionProvider>>> {
-    let mut providers: HashMap<String, Vec<Box<dyn ActionProvider>>> = Default::default();
+) -> (ProviderMap, Vec<LoadError>) {
+    let mut providers: ProviderMap = Default::default();
+    let mut errors = Vec::new();
 
-    //log::info!("Processing  config-dir: {:?}", config_dir);
+    log::info!("loading code actions from {:?}", code_actions_config_dir);
     for language in SUPPORTED_LANGUAGES {
         let config_dir = code_actions_config_dir.join(language);
         for config_path in read_language_config_files(&config_dir, "yaml") {
-            //log::info!("Processing language config: {:?}", config_path);
             match config::CodeActionConfig::from_yaml(&config_path) {
                 Ok(language_config) => {
                     for (k, config) in language_config.code_actions.into_iter().enumerate() {
-                        //log::info!("Register action {} for {:?}", config.name, config_path);
-                        providers
-                            .entry(language.to_owned())
-                            .or_default()
-                            .push(Box::new(YamlProvider::from_config(
-                                config,
-                                &format!("{}.{k}", config_path.to_string_lossy()),
-                                prompt_handler.clone(),
-                            )));
+                        log::debug!("register action {} for {:?}", config.name, config_path);
+                        let id = format!("{}.{k}", config_path.to_string_lossy());
+                        match YamlProvider::from_config(
+                            config,
+                            &id,
+                            language,
+                            prompt_handler.clone(),
+                        ) {
+                            Ok(provider) => providers
+                                .entry(language.to_owned())
+                                .or_default()
+                                .push(Box::new(provider)),
+                            Err(e) => {
+                                log::warn!("cannot load {id} because of {e}");
+                                errors.push(LoadError {
+                                    path: config_path.clone(),
+                                    message: e.to_string(),
+                                });
+                            }
+                        }
                     }
                 }
-                Err(_e) => {
-                    //log::warn!("Cannot read {:?} because of {}", &config_path, e);
+                Err(e) => {
+                    log::warn!("cannot read {:?} because of {}", &config_path, e);
+                    errors.push(LoadError {
+                        path: config_path,
+                        message: e.to_string(),
+                    });
                 }
             };
         }
         for config_path in read_language_config_files(&config_dir, "lua") {
-            //log::info!("Processing language config: {:?}", config_path);
-            providers
-                .entry(language.to_owned())
-                .or_default()
-                .push(Box::new(
-                    LuaProvider::try_new(&config_path.to_string_lossy(), prompt_handler.clone())
-                        .unwrap(),
-                ));
+            match LuaProvider::try_new(&config_path.to_string_lossy(), prompt_handler.clone()) {
+                Ok(provider) => {
+                    log::debug!(
+                        "register action {} for {:?}",
+                        provider.action_name(),
+                        config_path
+                    );
+                    providers
+                        .entry(language.to_owned())
+                        .or_default()
+                        .push(Box::new(provider))
+                }
+                Err(e) => {
+                    log::warn!("cannot read {:?} because of {}", &config_path, e);
+                    errors.push(LoadError {
+                        path: config_path,
+                        message: e.to_string(),
+                    })
+                }
+            }
         }
     }
+    (providers, errors)
+}
+
+/// The actions built into the server, which actions loaded from files with
+/// the same name replace.
+pub fn builtin_providers(prompt_handler: Arc<Llm>) -> ProviderMap {
+    let mut providers: ProviderMap = Default::default();
+    providers
+        .entry("__all__".to_owned())
+        .or_default()
+        .push(Box::new(DiagnosticFixProvider::new(prompt_handler)));
     providers
 }
 
+/// Layers `overlay` on top of `base`.
+///
+/// An overlay action replaces every base action of the same language that has
+/// the same name. All other overlay actions are appended.
+pub fn merge_providers(base: &mut ProviderMap, overlay: ProviderMap) {
+    for (language, overlay_providers) in overlay {
+        let base_providers = base.entry(language).or_default();
+        base_providers.retain(|provider| {
+            !overlay_providers
+                .iter()
+                .any(|p| p.action_name() == provider.action_name())
+        });
+        base_providers.extend(overlay_providers);
+    }
+}
+
+/// Removes all actions whose name is listed in `action_names`.
+pub fn disable_actions(providers: &mut ProviderMap, action_names: &[String]) {
+    for language_specific_providers in providers.values_mut() {
+        language_specific_providers
+            .retain(|provider| !action_names.contains(&provider.action_name()));
+    }
+}
+
+/// Returns true if `action` is the code action called `action_name`.
+///
+/// Both the plain name and the title shown in the editor are accepted.
+pub fn is_named(action: &CodeAction, action_name: &str) -> bool {
+    action.title == action_name || action.title == format!("Polyglot: {action_name}")
+}
+
+/// Asks all providers for `lang` and `__all__` for a code action at `range`.
+///
+/// The range and the diagnostics are expected in UTF8 byte columns, see
+/// `map_to_lsp`.
+pub fn collect_code_actions(
+    providers: &ProviderMap,
+    doc: &ParsedDocument,
+    lang: &str,
+    range: &Range,
+    diagnostics: &[Diagnostic],
+) -> Vec<CodeAction> {
+    let mut actions = vec![];
+    for target_lang in [lang, "__all__"] {
+        if let Some(language_specific_providers) = providers.get(target_lang) {
+            for provider in language_specific_providers.iter() {
+                if let Some(action) = provider.create_code_action(doc, range, diagnostics) {
+                    actions.push(action);
+                }
+            }
+        }
+    }
+    actions
+}
+
 pub fn find_resolver<'a>(
-    providers: &'a HashMap<String, Vec<Box<dyn ActionProvider>>>,
+    providers: &'a ProviderMap,
     code_action_id: &str,
     lang: &str,
 ) -> Option<&'a Box<dyn ActionProvider>> {
@@ -88,24 +202,55 @@ pub fn find_resolver<'a>(
     None
 }
 
+/// Converts an LSP range in UTF16 columns into the UTF8 byte columns the
+/// providers work with.
+pub fn range_from_lsp(range: &Range, index: &IndexedText<String>) -> Option<Range> {
+    let rs = index.lsp_range_to_range(range)?;
+    Some(Range::new(
+        Position::new(rs.start.line, rs.start.col),
+        Position::new(rs.end.line, rs.end.col),
+    ))
+}
+
+/// Converts the ranges of diagnostics sent by the client into UTF8 byte
+/// columns, dropping those outside of the document.
+pub fn diagnostics_from_lsp(
+    diagnostics: Vec<Diagnostic>,
+    index: &IndexedText<String>,
+) -> Vec<Diagnostic> {
+    diagnostics
+        .into_iter()
+        .filter_map(|diagnostic| {
+            Some(Diagnostic {
+                range: range_from_lsp(&diagnostic.range, index)?,
+                ..diagnostic
+            })
+        })
+        .collect()
+}
+
+/// Converts a range in UTF8 byte columns back into an LSP range.
+pub fn range_to_lsp(range: &Range, index: &IndexedText<String>) -> Option<Range> {
+    let fake = std::ops::Range::<nonsense::Pos> {
+        start: nonsense::Pos {
+            line: range.start.line,
+            col: range.start.character,
+        },
+        end: nonsense::Pos {
+            line: range.end.line,
+            col: range.end.character,
+        },
+    };
+    index.range_to_lsp_range(&fake)
+}
+
 pub fn map_to_lsp(r: &mut CodeAction, index: &IndexedText<String>) {
     // if let Ok(r) = r.as_mut() {
     if let Some(e) = r.edit.as_mut() {
         if let Some(c) = e.changes.as_mut() {
             for value in c.values_mut() {
                 for text_edit in value.iter_mut() {
-                    let fake = std::ops::Range::<nonsense::Pos> {
-                        start: nonsense::Pos {
-                            line: text_edit.range.start.line,
-                            col: text_edit.range.start.character,
-                        },
-                        end: nonsense::Pos {
-                            line: text_edit.range.end.line,
-                            col: text_edit.range.end.character,
-                        },
-                    };
-                    let rs = index.range_to_lsp_range(&fake).unwrap();
-                    text_edit.range = rs;
+                    text_edit.range = range_to_lsp(&text_edit.range, index).unwrap();
                 }
             }
         }
@@ -1,5 +1,5 @@
 use tower_lsp::lsp_types::Url;
-use tree_sitter::{Node, Parser, Point, Query, QueryCursor, Tree};
+use tree_sitter::{Language, Node, Parser, Point, Query, QueryCursor, Tree};
 
 pub struct ParsedDocument {
     pub tree: Tree,
@@ -10,15 +10,23 @@ pub struct ParsedDocument {
     pub uri: Url,
 }
 
+/// The languages with a tree-sitter grammar compiled in.
+pub const GRAMMARS: [&str; 3] = ["python", "rust", "go"];
+
+/// Returns the tree-sitter grammar compiled in for `lang`, if any.
+pub fn ts_language(lang: &str) -> Option<Language> {
+    match lang {
+        "python" => Some(tree_sitter_python::language()),
+        "rust" => Some(tree_sitter_rust::language()),
+        "go" => Some(tree_sitter_go::language()),
+        _ => None,
+    }
+}
+
 fn create_parser(lang: &str) -> Parser {
     let mut parser = Parser::new();
-    let language = match lang {
-        "python" => tree_sitter_python::language(),
-        "rust" => tree_sitter_rust::language(),
-        "go" => tree_sitter_go::language(),
-        // TODO(patwie): Better error handling
-        _ => tree_sitter_python::language(),
-    };
+    // TODO(patwie): Better error handling
+    let language = ts_language(lang).unwrap_or_else(tree_sitter_python::language);
 
     parser
         .set_language(&language)
@@ -84,6 +92,14 @@ impl ParsedDocument {
         }
     }
 
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
     pub fn update(&mut self, source: &str) {
         self.tree = self.parser.parse(source, Some(&self.tree)).unwrap();
         self.source = source.to_string();
@@ -111,15 +127,18 @@ impl ParsedDocument {
         )
     }
     pub fn query<'a>(&'a self, node: &'a Node, query: &str) -> Vec<Node> {
-        let q = Query::new(&self.tree.language(), query);
-        if q.is_err() {
-            return Vec::default();
+        match Query::new(&self.tree.language(), query) {
+            Ok(q) => self.captures(node, &q),
+            Err(_) => Vec::default(),
         }
-        let q = q.unwrap();
+    }
+
+    /// The nodes `q` captures below `node`, in match order.
+    pub fn captures<'a>(&'a self, node: &Node<'a>, q: &Query) -> Vec<Node<'a>> {
         let mut cursor = QueryCursor::new();
         cursor.set_byte_range(node.byte_range());
 
-        let matches = cursor.matches(&q, *node, self.source.as_bytes());
+        let matches = cursor.matches(q, *node, self.source.as_bytes());
         let mut nodes = Vec::new();
 
         for m in matches {
@@ -130,12 +149,30 @@ impl ParsedDocument {
         nodes
     }
 
+    /// Returns true if `q` matches below `node`, with or without captures.
+    pub fn matches(&self, node: &Node, q: &Query) -> bool {
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(node.byte_range());
+        let has_match = cursor
+            .matches(q, *node, self.source.as_bytes())
+            .next()
+            .is_some();
+        has_match
+    }
+
+    /// The first node `query` captures below `node`, `None` if the query is
+    /// invalid in the grammar of the document.
     pub fn find_first<'a>(&'a self, node: &'a Node, query: &str) -> Option<Node> {
-        let q = Query::new(&self.tree.language(), query).unwrap();
+        let q = Query::new(&self.tree.language(), query).ok()?;
+        self.first_capture(node, &q)
+    }
+
+    /// The first node `q` captures below `node`.
+    pub fn first_capture<'a>(&'a self, node: &Node<'a>, q: &Query) -> Option<Node<'a>> {
         let mut cursor = QueryCursor::new();
         cursor.set_byte_range(node.byte_range());
         let first_match = cursor
-            .matches(&q, *node, self.source.as_bytes())
+            .matches(q, *node, self.source.as_bytes())
             .flat_map(|m| m.captures)
             .next();
         first_match.map(|m| m.node)
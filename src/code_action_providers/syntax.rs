@@ -0,0 +1,208 @@
+//! Checks that an edit does not add syntax errors to a document.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tower_lsp::jsonrpc;
+use tower_lsp::lsp_types::{CodeAction, TextEdit};
+use tree_sitter::Node;
+
+use super::candidates::{self, CandidateSource, Completion};
+use super::helper;
+use super::parsed_document::{ts_language, ParsedDocument};
+use crate::llm_handlers::traits::Llm;
+
+/// What happens to an answer whose edit adds syntax errors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SyntaxCheck {
+    /// The edit is applied unchecked.
+    #[default]
+    Off,
+    /// The action fails with the syntax error.
+    Reject,
+    /// The model is asked again with the syntax error, then the action fails.
+    Retry,
+}
+
+impl SyntaxCheck {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(SyntaxCheck::Off),
+            "reject" => Some(SyntaxCheck::Reject),
+            "retry" => Some(SyntaxCheck::Retry),
+            _ => None,
+        }
+    }
+}
+
+/// How often `SyntaxCheck::Retry` asks the model again.
+pub const MAX_RETRIES: usize = 1;
+
+/// The ERROR and MISSING nodes below `node`.
+fn syntax_errors<'a>(node: Node<'a>, errors: &mut Vec<Node<'a>>) {
+    if node.is_error() || node.is_missing() {
+        errors.push(node);
+        return;
+    }
+    if !node.has_error() {
+        return;
+    }
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        syntax_errors(child, errors);
+    }
+}
+
+fn describe(doc: &ParsedDocument, node: &Node) -> String {
+    let line = node.start_position().row + 1;
+    if node.is_missing() {
+        return format!("line {line}: missing `{}`", node.kind());
+    }
+    let text = doc.text_from_node(node);
+    let text = text.lines().next().unwrap_or_default().trim();
+    format!("line {line}: unexpected `{text}`")
+}
+
+/// Applies `edit` to a copy of `doc` and fails with a description of the
+/// first new syntax error if the copy has more errors than `doc`.
+///
+/// Documents without a grammar are not checked.
+pub fn check_edit(doc: &ParsedDocument, edit: &TextEdit) -> Result<(), String> {
+    if ts_language(doc.language()).is_none() {
+        return Ok(());
+    }
+    let mut before = Vec::new();
+    syntax_errors(doc.tree.root_node(), &mut before);
+    let edited = ParsedDocument::new(
+        &helper::apply_text_edit(doc.source(), edit),
+        &doc.uri,
+        doc.language(),
+    );
+    let mut after = Vec::new();
+    syntax_errors(edited.tree.root_node(), &mut after);
+    if after.len() <= before.len() {
+        return Ok(());
+    }
+    // Errors ending above the edit were there before, and one starting above
+    // it usually swallowed the code around the edit.
+    let edit_line = edit.range.start.line as usize;
+    let first = after
+        .iter()
+        .find(|node| node.end_position().row >= edit_line)
+        .unwrap_or(&after[0]);
+    let error = if first.start_position().row < edit_line {
+        format!(
+            "line {}: cannot parse the code around the edit",
+            edit_line + 1
+        )
+    } else {
+        describe(&edited, first)
+    };
+    Err(format!("the edit adds syntax errors, the first at {error}"))
+}
+
+/// The prompt asking the model again after `answer` to `prompt` failed the
+/// check with `error`.
+pub fn retry_prompt(prompt: &str, answer: &str, error: &str) -> String {
+    format!(
+        "{prompt}\n\nYour previous answer was:\n{answer}\n\n\
+         Applying it to the file failed the syntax check: {error}. \
+         Answer again in the same format, without the syntax error."
+    )
+}
+
+/// The candidates an action asks the model for.
+pub struct CandidateRequest<'a> {
+    pub llm: &'a Llm,
+    /// The name of the action, for the usage and the errors.
+    pub action: &'a str,
+    /// The id of the provider, for the log.
+    pub id: &'a str,
+    pub count: usize,
+    pub source: CandidateSource,
+    pub check: SyntaxCheck,
+}
+
+/// Asks the model for the candidates of `request` and turns each of them into
+/// a code action with `to_action`, which gets the prompt, the completion and
+/// the candidate. It returns `None` to drop the candidate, its first error is
+/// returned if no candidate makes an action.
+///
+/// Candidates whose edits add syntax errors to `doc` are dropped, and so are
+/// candidates with the title of an earlier one. If all candidates failed the
+/// syntax check, the model is asked again with `SyntaxCheck::Retry`, then the
+/// action fails with the syntax error.
+pub async fn resolve_candidates<F>(
+    request: CandidateRequest<'_>,
+    doc: &ParsedDocument,
+    base_prompt: &str,
+    mut to_action: F,
+) -> jsonrpc::Result<Vec<CodeAction>>
+where
+    F: FnMut(&str, &Completion, &str) -> jsonrpc::Result<Option<CodeAction>>,
+{
+    let mut prompt = base_prompt.to_owned();
+    let mut retries = 0;
+    loop {
+        log::debug!("prompt of {}: {}", request.id, prompt);
+        let completions = candidates::complete(
+            request.llm,
+            &prompt,
+            request.action,
+            request.count,
+            request.source,
+        )
+        .await?;
+        let mut actions: Vec<CodeAction> = Vec::new();
+        let mut error = None;
+        let mut syntax_error = None;
+        for completion in completions.iter() {
+            log::debug!("answer of {}: {}", request.id, completion.answer.text);
+            for candidate in completion.candidates.iter() {
+                let action = match to_action(&prompt, completion, candidate) {
+                    Ok(Some(action)) => action,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error.get_or_insert(e);
+                        continue;
+                    }
+                };
+                if request.check != SyntaxCheck::Off {
+                    let checked = action
+                        .edit
+                        .iter()
+                        .filter_map(|edit| edit.changes.as_ref()?.get(&doc.uri))
+                        .flatten()
+                        .try_for_each(|edit| check_edit(doc, edit));
+                    if let Err(e) = checked {
+                        log::info!("{} failed the syntax check: {e}", request.id);
+                        syntax_error.get_or_insert((e, completion.answer.text.clone()));
+                        continue;
+                    }
+                }
+                // The titles tell the candidates apart.
+                let is_duplicate =
+                    request.count > 1 && actions.iter().any(|other| other.title == action.title);
+                if !is_duplicate {
+                    actions.push(action);
+                }
+            }
+        }
+        if !actions.is_empty() {
+            return Ok(actions);
+        }
+        if let Some((e, answer)) = syntax_error {
+            if request.check == SyntaxCheck::Retry && retries < MAX_RETRIES {
+                log::info!("asking {} again after the syntax check failed", request.id);
+                prompt = retry_prompt(base_prompt, &answer, &e);
+                retries += 1;
+                continue;
+            }
+            return Err(helper::internal_error(format!("{}: {e}", request.action)));
+        }
+        return match error {
+            Some(e) => Err(e),
+            None => Ok(Vec::new()),
+        };
+    }
+}
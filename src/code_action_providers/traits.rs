@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use tower_lsp::{
     jsonrpc::Result,
-    lsp_types::{CodeAction, Url},
+    lsp_types::{CodeAction, Diagnostic, Range, Url},
 };
 
 use super::parsed_document::ParsedDocument;
@@ -11,15 +11,57 @@ use async_trait::async_trait;
 pub struct ActionContext {
     pub uri: Url,
     pub selection_range: tower_lsp::lsp_types::Range,
+    /// The range selected when the action was offered, if it differs from
+    /// `selection_range`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selection: Option<Range>,
+    /// The diagnostics of other servers at the range the action was offered
+    /// for.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// The tree-sitter node an action takes its context from.
+#[derive(Debug, Serialize)]
+pub struct ContextNode {
+    pub kind: String,
+    pub range: Range,
+}
+
+/// What resolving an action would send to the model and where the answer
+/// would be placed.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PromptPreview {
+    pub action: String,
+    pub prompt: String,
+    pub placement_range: Option<Range>,
+    pub context_node: Option<ContextNode>,
 }
 
 #[async_trait]
 pub trait ActionProvider: Send + Sync {
+    /// The human readable name of the action, used for overriding and disabling.
+    fn action_name(&self) -> String;
     fn can_handle(&self, action_name: &str) -> bool;
     async fn on_resolve(&self, doc: &ParsedDocument, action: CodeAction) -> Result<CodeAction>;
+    /// Resolves the action into one action per candidate answer, none if the
+    /// answer cannot be placed.
+    async fn on_resolve_candidates(
+        &self,
+        doc: &ParsedDocument,
+        action: CodeAction,
+    ) -> Result<Vec<CodeAction>> {
+        Ok(vec![self.on_resolve(doc, action).await?])
+    }
+    /// Runs `on_resolve` up to the point where the model would be asked.
+    fn preview(&self, doc: &ParsedDocument, action: &CodeAction) -> Result<PromptPreview>;
+    /// The action offered at `start_range`, where the client reported
+    /// `diagnostics`, if it is triggered there.
     fn create_code_action(
         &self,
         doc: &ParsedDocument,
         start_range: &tower_lsp::lsp_types::Range,
+        diagnostics: &[Diagnostic],
     ) -> Option<tower_lsp::lsp_types::CodeAction>;
 }
@@ -1,15 +1,28 @@
+use anyhow::{anyhow, bail};
+use globset::{GlobBuilder, GlobMatcher};
+use minijinja::Value;
+use regex::Regex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use tree_sitter::Node;
+use tower_lsp::lsp_types::Range;
+use tree_sitter::{Language, Node, Point, Query};
 
+use crate::code_action_providers::candidates::CandidateSource;
 use crate::code_action_providers::helper::findup;
+use crate::code_action_providers::parsed_document::ParsedDocument;
+use crate::code_action_providers::syntax::SyntaxCheck;
 
-#[derive(Debug, Deserialize, Serialize)]
+use super::template;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct CodeActionConfig {
     pub code_actions: Vec<CodeAction>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct CodeAction {
     /// The name of the code action.
     pub name: String,
@@ -19,32 +32,310 @@ pub struct CodeAction {
     pub context: Context,
     /// The placement strategies that determine where the result is displayed.
     pub placement_strategies: Vec<PlacementStrategy>,
-    /// The template used to generate the prompt for this code action.
+    /// The minijinja template used to generate the prompt for this code action.
     pub prompt_template: String,
-    /// The template used to embed the answer for this code action, if applicable.
+    /// Steps cleaning up the answer before `answer_template`, in order.
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    #[schemars(with = "Vec<PostprocessStep>")]
+    pub postprocess: Vec<PostprocessStep>,
+    /// The minijinja template used to embed the answer, available as `ANSWER`.
     pub answer_template: Option<String>,
+    /// Whether answers may come from the response cache, `true` if unset.
+    pub cache: Option<bool>,
+    /// What happens to an answer whose edit adds syntax errors.
+    #[serde(default)]
+    pub validate_syntax: SyntaxCheck,
+    /// How many answers to offer to choose from, 1 if unset.
+    pub candidates: Option<usize>,
+    /// Where the candidates come from.
+    #[serde(default)]
+    pub candidate_source: CandidateSource,
+    /// The template of the title of a candidate, with the candidate as
+    /// `ANSWER`. Its first line if unset.
+    pub candidate_title: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// When a code action is offered. All conditions set must hold.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Trigger {
-    kind: String,
+    /// The node kind looked for from the cursor, the conditions below apply
+    /// to the node found. The node under the cursor if unset.
+    kind: Option<String>,
+    #[serde(default)]
     relation: Relation,
+    /// A tree-sitter query that must match below the node, captures are not
+    /// needed.
+    matches: Option<String>,
+    /// A tree-sitter query that must not match below the node.
+    not_matches: Option<String>,
+    /// A tree-sitter query below the node with a capture under the cursor.
+    cursor_in: Option<String>,
+    /// A regex the text of the node must match.
+    regex: Option<String>,
+    /// A glob the path of the document must match, e.g. `tests/**`.
+    path: Option<String>,
+    /// Whether text must be selected or not.
+    selection: Option<SelectionState>,
+    /// Triggers of which at least one must hold.
+    any: Option<Vec<Trigger>>,
+    /// Triggers which all must hold.
+    all: Option<Vec<Trigger>>,
+    /// A trigger which must not hold.
+    not: Option<Box<Trigger>>,
 }
 
-impl Trigger {
-    pub fn is_triggered(&self, start_node: Option<Node>) -> bool {
-        if let Some(node) = start_node {
-            match self.relation {
-                Relation::Findup => findup(Some(node), &self.kind).is_some(),
-                Relation::Exact => node.kind() == self.kind,
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionState {
+    Empty,
+    NonEmpty,
+}
+
+/// Compiles `pattern`, which matches anywhere in a path unless it starts with
+/// `/` or `**`.
+fn path_glob(pattern: &str) -> Result<GlobMatcher, globset::Error> {
+    let pattern = if pattern.starts_with('/') || pattern.starts_with("**") {
+        pattern.to_owned()
+    } else {
+        format!("**/{pattern}")
+    };
+    Ok(GlobBuilder::new(&pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher())
+}
+
+/// The explanation of a regex syntax error, which spans several lines of which
+/// the last one explains it.
+fn regex_error(e: &regex::Error) -> String {
+    e.to_string()
+        .lines()
+        .last()
+        .unwrap_or_default()
+        .trim_start_matches("error: ")
+        .to_owned()
+}
+
+fn contains(node: &Node, range: &Range) -> bool {
+    let start = Point::new(range.start.line as usize, range.start.character as usize);
+    let end = Point::new(range.end.line as usize, range.end.character as usize);
+    node.start_position() <= start && end <= node.end_position()
+}
+
+/// A query compiled for each grammar it may run against.
+pub struct CompiledQuery(Vec<(Language, Query)>);
+
+impl CompiledQuery {
+    /// Compiles the `what` query `query` for `grammars`, failing if it is
+    /// valid in none of them.
+    pub fn new(what: &str, query: &str, grammars: &[Language]) -> anyhow::Result<Self> {
+        let mut compiled = Vec::new();
+        let mut error = None;
+        for language in grammars {
+            match Query::new(language, query) {
+                Ok(q) => compiled.push((language.clone(), q)),
+                Err(e) => error = Some(e),
             }
-        } else {
-            false
+        }
+        match error {
+            Some(e) if compiled.is_empty() => bail!(
+                "invalid {what} query `{}` ({:?}): {}",
+                query.trim(),
+                e.kind,
+                e.message.trim()
+            ),
+            _ => Ok(Self(compiled)),
+        }
+    }
+
+    /// The query compiled for the grammar of `doc`, if it is valid there.
+    pub fn get(&self, doc: &ParsedDocument) -> Option<&Query> {
+        let language = doc.tree.language();
+        self.0
+            .iter()
+            .find(|(grammar, _)| *grammar == *language)
+            .map(|(_, query)| query)
+    }
+}
+
+/// A `Trigger` whose queries, regex and glob are compiled when the action is
+/// loaded.
+pub struct CompiledTrigger {
+    kind: Option<String>,
+    relation: Relation,
+    matches: Option<CompiledQuery>,
+    not_matches: Option<CompiledQuery>,
+    cursor_in: Option<CompiledQuery>,
+    regex: Option<Regex>,
+    path: Option<GlobMatcher>,
+    selection: Option<SelectionState>,
+    any: Option<Vec<CompiledTrigger>>,
+    all: Option<Vec<CompiledTrigger>>,
+    not: Option<Box<CompiledTrigger>>,
+}
+
+impl CompiledTrigger {
+    /// The node the conditions apply to, `None` if `kind` is not found from
+    /// `start_node`.
+    fn node<'a>(&self, start_node: Node<'a>) -> Option<Node<'a>> {
+        match (&self.kind, &self.relation) {
+            (None, _) => Some(start_node),
+            (Some(kind), Relation::Findup) => findup(Some(start_node), kind),
+            (Some(kind), Relation::Exact) => Some(start_node).filter(|node| node.kind() == kind),
+        }
+    }
+
+    /// Returns true if the action should be offered for the selection `range`.
+    pub fn is_triggered(&self, doc: &ParsedDocument, range: &Range) -> bool {
+        doc.get_ts_node_for_range(range)
+            .is_some_and(|cursor_node| self.holds(doc, range, cursor_node))
+    }
+
+    /// Checks the conditions from `start_node`. Nested triggers start from the
+    /// node of their parent. A query not valid in the grammar of `doc` never
+    /// matches.
+    fn holds(&self, doc: &ParsedDocument, range: &Range, start_node: Node) -> bool {
+        let Some(node) = self.node(start_node) else {
+            return false;
+        };
+        if let Some(query) = &self.matches {
+            if !query.get(doc).is_some_and(|q| doc.matches(&node, q)) {
+                return false;
+            }
+        }
+        if let Some(query) = &self.not_matches {
+            if query.get(doc).is_some_and(|q| doc.matches(&node, q)) {
+                return false;
+            }
+        }
+        if let Some(query) = &self.cursor_in {
+            let Some(q) = query.get(doc) else {
+                return false;
+            };
+            if !doc
+                .captures(&node, q)
+                .iter()
+                .any(|node| contains(node, range))
+            {
+                return false;
+            }
+        }
+        if let Some(regex) = &self.regex {
+            if !regex.is_match(&doc.text_from_node(&node)) {
+                return false;
+            }
+        }
+        if let Some(glob) = &self.path {
+            match doc.uri.to_file_path() {
+                Ok(path) if glob.is_match(&path) => {}
+                _ => return false,
+            }
+        }
+        let is_empty = range.start == range.end;
+        match self.selection {
+            Some(SelectionState::Empty) if !is_empty => return false,
+            Some(SelectionState::NonEmpty) if is_empty => return false,
+            _ => {}
+        }
+        if let Some(any) = &self.any {
+            if !any.iter().any(|trigger| trigger.holds(doc, range, node)) {
+                return false;
+            }
+        }
+        if let Some(all) = &self.all {
+            if !all.iter().all(|trigger| trigger.holds(doc, range, node)) {
+                return false;
+            }
+        }
+        if let Some(not) = &self.not {
+            if not.holds(doc, range, node) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Trigger {
+    /// Compiles the trigger for the documents parsed with `grammars`.
+    pub fn compile(&self, grammars: &[Language]) -> anyhow::Result<CompiledTrigger> {
+        let query = |query: &Option<String>| {
+            query
+                .as_deref()
+                .map(|query| CompiledQuery::new("trigger", query, grammars))
+                .transpose()
+        };
+        let triggers = |triggers: &Option<Vec<Trigger>>| {
+            triggers
+                .as_ref()
+                .map(|triggers| {
+                    triggers
+                        .iter()
+                        .map(|trigger| trigger.compile(grammars))
+                        .collect::<anyhow::Result<Vec<_>>>()
+                })
+                .transpose()
+        };
+        Ok(CompiledTrigger {
+            kind: self.kind.clone(),
+            relation: self.relation,
+            matches: query(&self.matches)?,
+            not_matches: query(&self.not_matches)?,
+            cursor_in: query(&self.cursor_in)?,
+            regex: self
+                .regex
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(|e| anyhow!("invalid trigger regex: {}", regex_error(&e)))?,
+            path: self
+                .path
+                .as_deref()
+                .map(path_glob)
+                .transpose()
+                .map_err(|e| anyhow!("invalid trigger path: {e}"))?,
+            selection: self.selection,
+            any: triggers(&self.any)?,
+            all: triggers(&self.all)?,
+            not: match &self.not {
+                Some(not) => Some(Box::new(not.compile(grammars)?)),
+                None => None,
+            },
+        })
+    }
+
+    fn validate(&self, language: &Language, errors: &mut Vec<ValidationError>) {
+        if let Some(kind) = &self.kind {
+            validate_kind(language, kind, errors);
+        }
+        let queries = [&self.matches, &self.not_matches, &self.cursor_in];
+        for query in queries.into_iter().flatten() {
+            validate_query(language, query, errors);
+        }
+        if let Some(Err(e)) = self.regex.as_deref().map(Regex::new) {
+            errors.push(ValidationError {
+                query: None,
+                row: 0,
+                message: format!("invalid trigger regex: {}", regex_error(&e)),
+            });
+        }
+        if let Some(Err(e)) = self.path.as_deref().map(path_glob) {
+            errors.push(ValidationError {
+                query: None,
+                row: 0,
+                message: format!("invalid trigger path: {e}"),
+            });
+        }
+        let nested = self.any.iter().chain(&self.all).flatten();
+        for trigger in nested.chain(self.not.as_deref()) {
+            trigger.validate(language, errors);
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Context {
     kind: String,
     relation: Relation,
@@ -69,27 +360,203 @@ impl Context {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// A variable of the templates, taken from the captures of `query` or from
+/// text around the context node.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Hint {
     pub name: String,
-    pub query: String,
+    /// The tree-sitter query whose captures form the hint.
+    pub query: Option<String>,
+    /// Whether the first or all captures of `query` form the hint.
+    #[serde(default)]
+    pub mode: HintMode,
+    /// Put between the captures of `mode: all` when the hint is printed as a
+    /// whole, a newline if unset.
+    pub separator: Option<String>,
+    /// The part of the document `query` searches.
+    #[serde(default)]
+    pub scope: HintScope,
+    /// The lines above the context node, instead of a query.
+    pub lines_before: Option<usize>,
+    /// The lines below the context node, instead of a query.
+    pub lines_after: Option<usize>,
+    /// The selected text, instead of a query.
+    #[serde(default)]
+    pub selection: bool,
+    /// Longer hints are cut to this many characters.
+    pub max_length: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HintMode {
+    #[default]
+    First,
+    All,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HintScope {
+    /// The context node.
+    #[default]
+    Context,
+    /// The class, impl or trait around the context node, the file if there is
+    /// none.
+    Class,
+    /// The whole document.
+    File,
+}
+
+/// The node kinds `HintScope::Class` looks for in `language`.
+fn class_kinds(language: &str) -> &'static [&'static str] {
+    match language {
+        "python" => &["class_definition"],
+        "rust" => &["impl_item", "trait_item"],
+        _ => &[],
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Hint {
+    /// The node `query` searches below.
+    fn scope_node<'a>(&self, doc: &'a ParsedDocument, ctx_node: &Node<'a>) -> Node<'a> {
+        let root = doc.tree.root_node();
+        match self.scope {
+            HintScope::Context => *ctx_node,
+            HintScope::Class => class_kinds(doc.language())
+                .iter()
+                .filter_map(|kind| findup(Some(*ctx_node), kind))
+                .min_by_key(|node| node.byte_range().len())
+                .unwrap_or(root),
+            HintScope::File => root,
+        }
+    }
+
+    /// The value of the hint for the context node `ctx_node`, `None` if the
+    /// hint is empty. `query` is the compiled `self.query`, if it is valid in
+    /// the grammar of `doc`. With `mode: all` the value is a list of the
+    /// captures.
+    pub fn value(
+        &self,
+        doc: &ParsedDocument,
+        ctx_node: &Node,
+        selection: Option<&Range>,
+        query: Option<&Query>,
+    ) -> Option<Value> {
+        let start_line = ctx_node.start_position().row;
+        let end_line = ctx_node.end_position().row;
+        let text = if let Some(count) = self.lines_before {
+            template::lines_around(doc.source(), start_line, end_line, count).0
+        } else if let Some(count) = self.lines_after {
+            template::lines_around(doc.source(), start_line, end_line, count).1
+        } else if self.selection {
+            doc.text_from_range(selection?)
+        } else {
+            let query = query?;
+            let scope = self.scope_node(doc, ctx_node);
+            match self.mode {
+                HintMode::First => doc.text_from_node(&doc.first_capture(&scope, query)?),
+                HintMode::All => return self.captures(doc, &scope, query),
+            }
+        };
+        if text.is_empty() {
+            return None;
+        }
+        Some(Value::from(match self.max_length {
+            Some(max_length) => text.chars().take(max_length).collect(),
+            None => text,
+        }))
+    }
+
+    /// The captures of `query` below `scope`. `max_length` limits them
+    /// together, as if printed with `separator`.
+    fn captures(&self, doc: &ParsedDocument, scope: &Node, query: &Query) -> Option<Value> {
+        let separator = self.separator.clone().unwrap_or_else(|| "\n".to_owned());
+        let mut nodes = doc.captures(scope, query);
+        nodes.dedup_by_key(|node| node.id());
+        let mut left = self.max_length.unwrap_or(usize::MAX);
+        let mut texts = Vec::new();
+        for node in nodes {
+            if left == 0 {
+                break;
+            }
+            let text: String = doc.text_from_node(&node).chars().take(left).collect();
+            left -= text.chars().count();
+            left = left.saturating_sub(separator.chars().count());
+            texts.push(text);
+        }
+        if texts.iter().all(|text| text.is_empty()) {
+            return None;
+        }
+        Some(Value::from_object(template::Captures { texts, separator }))
+    }
+
+    fn validate(&self, language: &Language, errors: &mut Vec<ValidationError>) {
+        let sources = [
+            self.query.is_some(),
+            self.lines_before.is_some(),
+            self.lines_after.is_some(),
+            self.selection,
+        ];
+        if sources.iter().filter(|source| **source).count() != 1 {
+            errors.push(ValidationError {
+                query: None,
+                row: 0,
+                message: format!(
+                    "hint `{}` needs exactly one of `query`, `lines_before`, `lines_after` \
+                     and `selection`",
+                    self.name
+                ),
+            });
+        }
+        if let Some(query) = &self.query {
+            validate_query(language, query, errors);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct PlacementStrategy {
-    pub query: String,
+    /// The node the answer is placed relative to, the context node if unset.
+    pub query: Option<String>,
     pub position: Position,
+    /// For `new_file`, the template of the file path, relative to the
+    /// directory of the document.
+    pub path: Option<String>,
+}
+
+/// A step of `postprocess`, written as `- dedent` or `- wrap: 88`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PostprocessStep {
+    /// Keep only the content of the first markdown code fence.
+    StripFences,
+    /// Keep only the text between `<tag>` and `</tag>`.
+    ExtractTag(String),
+    /// Drop leading lines like "Here is the docstring:".
+    DropLeadingProse,
+    /// Remove the indentation common to all lines.
+    Dedent,
+    /// Dedent, then indent all lines by this many spaces.
+    Reindent(usize),
+    /// Break lines longer than this many columns.
+    Wrap(usize),
+    /// Reject the answer unless it parses as a single node of this kind.
+    Validate(String),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, JsonSchema)]
 enum Relation {
+    #[default]
     #[serde(rename = "findup")]
     Findup,
     #[serde(rename = "exact")]
     Exact,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum Position {
     #[serde(rename = "replace_block")]
@@ -98,6 +565,111 @@ pub enum Position {
     ReplaceExact,
     #[serde(rename = "before")]
     Before,
+    /// Insert on the lines below the node.
+    #[serde(rename = "after")]
+    After,
+    /// Insert as the first statement of the block the query matched.
+    #[serde(rename = "prepend_to_body")]
+    PrependToBody,
+    /// Insert as the last statement of the block the query matched.
+    #[serde(rename = "append_to_body")]
+    AppendToBody,
+    /// Replace the text selected when the action was offered.
+    #[serde(rename = "replace_selection")]
+    ReplaceSelection,
+    /// Insert at the cursor position.
+    #[serde(rename = "insert_at_cursor")]
+    InsertAtCursor,
+    /// Append to the end of the document.
+    #[serde(rename = "end_of_file")]
+    EndOfFile,
+    /// Create the file `path` holding the answer.
+    #[serde(rename = "new_file")]
+    NewFile,
+}
+
+/// A problem found while validating a code action against its grammar.
+#[derive(Debug)]
+pub struct ValidationError {
+    /// The query the problem was found in, if any.
+    pub query: Option<String>,
+    /// The 0-based row inside `query`.
+    pub row: usize,
+    pub message: String,
+}
+
+fn validate_kind(language: &Language, kind: &str, errors: &mut Vec<ValidationError>) {
+    if language.id_for_node_kind(kind, true) == 0 {
+        errors.push(ValidationError {
+            query: None,
+            row: 0,
+            message: format!("unknown node kind `{kind}`"),
+        });
+    }
+}
+
+fn validate_query(language: &Language, query: &str, errors: &mut Vec<ValidationError>) {
+    if let Err(e) = Query::new(language, query) {
+        errors.push(ValidationError {
+            query: Some(query.to_owned()),
+            row: e.row,
+            message: format!("invalid query ({:?}): {}", e.kind, e.message.trim()),
+        });
+    }
+}
+
+impl CodeAction {
+    /// Checks all node kinds and tree-sitter queries against `language`.
+    pub fn validate(&self, language: &Language) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        for trigger in self.triggers.iter() {
+            trigger.validate(language, &mut errors);
+        }
+        validate_kind(language, &self.context.kind, &mut errors);
+        for hint in self.context.hints.iter() {
+            hint.validate(language, &mut errors);
+        }
+        for placement in self.placement_strategies.iter() {
+            if let Some(query) = &placement.query {
+                validate_query(language, query, &mut errors);
+            }
+            match (&placement.position, &placement.path) {
+                (Position::NewFile, None) => errors.push(ValidationError {
+                    query: None,
+                    row: 0,
+                    message: "position `new_file` needs a `path`".to_owned(),
+                }),
+                (Position::NewFile, Some(path)) => {
+                    if let Err((_, message)) = template::validate(path) {
+                        errors.push(ValidationError {
+                            query: None,
+                            row: 0,
+                            message: format!("invalid path template: {message}"),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        for step in self.postprocess.iter() {
+            if let PostprocessStep::Validate(kind) = step {
+                validate_kind(language, kind, &mut errors);
+            }
+        }
+        let templates = std::iter::once(&self.prompt_template)
+            .chain(&self.answer_template)
+            .chain(&self.candidate_title);
+        for template in templates {
+            if let Err((line, message)) = template::validate(template) {
+                errors.push(ValidationError {
+                    query: Some(template.clone()),
+                    row: line - 1,
+                    message: format!("invalid template: {message}"),
+                });
+            }
+        }
+        errors
+    }
 }
 
 impl CodeActionConfig {
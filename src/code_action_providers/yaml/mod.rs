@@ -1,2 +1,5 @@
 pub mod config;
+pub mod placement;
+pub mod postprocess;
 pub mod provider;
+pub mod template;
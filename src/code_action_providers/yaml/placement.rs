@@ -0,0 +1,410 @@
+//! Where the answer of a YAML action goes and how it is indented there.
+
+use tower_lsp::lsp_types::{Position as LspPosition, Range};
+use tree_sitter::Node;
+
+use crate::code_action_providers::helper;
+use crate::code_action_providers::parsed_document::ParsedDocument;
+
+use super::config::Position;
+
+/// The node a placement query matched and the user's selection.
+pub struct Anchor<'a> {
+    pub doc: &'a ParsedDocument,
+    pub node: Node<'a>,
+    pub selection: Range,
+}
+
+/// One level of indentation in `language`, used for empty bodies.
+fn indent_unit(language: &str) -> &'static str {
+    match language {
+        "go" => "\t",
+        _ => "    ",
+    }
+}
+
+fn source_line(doc: &ParsedDocument, row: usize) -> &str {
+    doc.source().lines().nth(row).unwrap_or_default()
+}
+
+/// The leading whitespace of `line`.
+fn line_indent(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Returns true if only whitespace precedes `node` on its first line.
+fn starts_line(doc: &ParsedDocument, node: &Node) -> bool {
+    let start = node.start_position();
+    source_line(doc, start.row)
+        .get(..start.column)
+        .is_some_and(|prefix| prefix.trim().is_empty())
+}
+
+/// The whitespace in front of `node`, or the indentation of its first line if
+/// other code precedes it there.
+fn node_indent(doc: &ParsedDocument, node: &Node) -> String {
+    let start = node.start_position();
+    let line = source_line(doc, start.row);
+    match line.get(..start.column) {
+        Some(prefix) if prefix.trim().is_empty() => prefix.to_owned(),
+        _ => line_indent(line).to_owned(),
+    }
+}
+
+/// The indentation of the statements in the block `body`, one level deeper
+/// than its header if they are on the header's line, like in `def f(): pass`.
+fn body_indent(doc: &ParsedDocument, body: &Node) -> String {
+    match body.named_child(0) {
+        Some(child) if starts_line(doc, &child) => node_indent(doc, &child),
+        _ => node_indent(doc, body) + indent_unit(doc.language()),
+    }
+}
+
+/// Indents all lines of `text` but the first, which continues a line.
+fn indent_continuation(text: &str, indent: &str) -> String {
+    let mut lines = text.lines();
+    let first = lines.next().unwrap_or_default().to_owned();
+    lines.fold(first, |text, line| format!("{text}\n{indent}{line}"))
+}
+
+fn point(row: usize, column: usize) -> LspPosition {
+    LspPosition::new(row as u32, column as u32)
+}
+
+fn empty_range(position: LspPosition) -> Range {
+    Range::new(position, position)
+}
+
+/// The end of the document.
+fn end_of_file(doc: &ParsedDocument) -> LspPosition {
+    let source = doc.source();
+    let row = source.matches('\n').count();
+    let column = source.len() - source.rfind('\n').map_or(0, |idx| idx + 1);
+    point(row, column)
+}
+
+/// Where `prepend_to_body`, or with `append` `append_to_body`, inserts into
+/// the block `body`.
+fn body_range(anchor: &Anchor, append: bool) -> Range {
+    let body = &anchor.node;
+    let children = body.named_child_count();
+    match (append, children) {
+        // An empty body like `{}`, insert after the opening delimiter.
+        (_, 0) => {
+            let start = body.start_position();
+            empty_range(point(start.row, start.column + 1))
+        }
+        (false, _) => {
+            let first = body.named_child(0).expect("body has a child");
+            let start = first.start_position();
+            if starts_line(anchor.doc, &first) {
+                return empty_range(point(start.row, 0));
+            }
+            // The body continues its header's line, the answer and the first
+            // statement move to lines of their own, without trailing spaces.
+            let line = source_line(anchor.doc, start.row);
+            let code_end = line[..start.column].trim_end().len();
+            Range::new(point(start.row, code_end), point(start.row, start.column))
+        }
+        (true, _) => {
+            let last = body.named_child(children - 1).expect("body has a child");
+            empty_range(helper::ts_point_to_lsp_position(&last.end_position()))
+        }
+    }
+}
+
+/// The range the answer replaces. For `new_file` this is the start of the new
+/// file.
+pub fn placement_range(position: &Position, anchor: &Anchor) -> Range {
+    let node = &anchor.node;
+    match position {
+        Position::ReplaceBlock => {
+            let mut placement_range = helper::ts_node_to_lsp_range(node);
+            placement_range.start.character = 0;
+            placement_range
+        }
+        Position::ReplaceExact => helper::ts_node_to_lsp_range(node),
+        Position::Before => helper::prepend_ts_node_to_lsp_range(node),
+        Position::After => empty_range(helper::ts_point_to_lsp_position(&node.end_position())),
+        Position::PrependToBody => body_range(anchor, false),
+        Position::AppendToBody => body_range(anchor, true),
+        Position::ReplaceSelection => anchor.selection,
+        Position::InsertAtCursor => empty_range(anchor.selection.start),
+        Position::EndOfFile => empty_range(end_of_file(anchor.doc)),
+        Position::NewFile => Range::default(),
+    }
+}
+
+/// The answer indented to fit at its placement.
+pub fn placement_text(position: &Position, anchor: &Anchor, answer: String) -> String {
+    let doc = anchor.doc;
+    let node = &anchor.node;
+    match position {
+        Position::ReplaceBlock => helper::prefix_lines(&answer, &node_indent(doc, node)),
+        Position::ReplaceExact => answer,
+        Position::Before => format!(
+            "{}\n",
+            helper::prefix_lines(&answer, &node_indent(doc, node))
+        ),
+        Position::After => format!(
+            "\n{}",
+            helper::prefix_lines(&answer, &node_indent(doc, node))
+        ),
+        Position::PrependToBody | Position::AppendToBody => {
+            let indent = body_indent(doc, node);
+            let text = helper::prefix_lines(&answer, &indent);
+            match (node.named_child_count(), position) {
+                // `{}` is split around the answer, `{\n}` has the newline already.
+                (0, _) if node.start_position().row == node.end_position().row => {
+                    format!("\n{text}\n{}", node_indent(doc, node))
+                }
+                (0, _) | (_, Position::AppendToBody) => format!("\n{text}"),
+                _ if !starts_line(doc, &node.named_child(0).expect("body has a child")) => {
+                    format!("\n{text}\n{indent}")
+                }
+                _ => format!("{text}\n"),
+            }
+        }
+        Position::ReplaceSelection | Position::InsertAtCursor => {
+            let line = source_line(doc, anchor.selection.start.line as usize);
+            indent_continuation(&answer, line_indent(line))
+        }
+        Position::EndOfFile => {
+            let source = doc.source();
+            let answer = answer.trim_end_matches('\n');
+            if source.is_empty() || source.ends_with('\n') {
+                format!("{answer}\n")
+            } else {
+                format!("\n{answer}\n")
+            }
+        }
+        Position::NewFile => format!("{}\n", answer.trim_end_matches('\n')),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower_lsp::lsp_types::{TextEdit, Url};
+
+    use super::*;
+
+    /// Places `answer` at the first node `query` captures in `source` and
+    /// returns the edited source.
+    fn place(
+        language: &str,
+        source: &str,
+        query: &str,
+        position: Position,
+        selection: Range,
+        answer: &str,
+    ) -> String {
+        let uri = Url::parse("file:///test").unwrap();
+        let doc = ParsedDocument::new(source, &uri, language);
+        let root = doc.tree.root_node();
+        let node = doc.query(&root, query)[0];
+        let anchor = Anchor {
+            doc: &doc,
+            node,
+            selection,
+        };
+        let edit = TextEdit {
+            range: placement_range(&position, &anchor),
+            new_text: placement_text(&position, &anchor, answer.to_owned()),
+        };
+        helper::apply_text_edit(source, &edit)
+    }
+
+    fn at_node(
+        language: &str,
+        source: &str,
+        query: &str,
+        position: Position,
+        answer: &str,
+    ) -> String {
+        place(language, source, query, position, Range::default(), answer)
+    }
+
+    #[test]
+    fn replace_block_keeps_the_indentation() {
+        let source = "class A:\n    def f(self):\n        return 1\n";
+        let edited = at_node(
+            "python",
+            source,
+            "(function_definition) @f",
+            Position::ReplaceBlock,
+            "def g(self):\n    return 2",
+        );
+        assert_eq!(edited, "class A:\n    def g(self):\n        return 2\n");
+    }
+
+    #[test]
+    fn replace_exact_replaces_only_the_node() {
+        let edited = at_node(
+            "rust",
+            "fn f() { a(); }\n",
+            "(call_expression) @c",
+            Position::ReplaceExact,
+            "b()",
+        );
+        assert_eq!(edited, "fn f() { b(); }\n");
+    }
+
+    #[test]
+    fn before_inserts_indented_lines_above() {
+        let source = "class A:\n    def f(self):\n        pass\n";
+        let edited = at_node(
+            "python",
+            source,
+            "(function_definition) @f",
+            Position::Before,
+            "@property",
+        );
+        assert_eq!(
+            edited,
+            "class A:\n    @property\n    def f(self):\n        pass\n"
+        );
+    }
+
+    #[test]
+    fn after_inserts_indented_lines_below() {
+        let source = "class A:\n    def f(self):\n        pass\n";
+        let edited = at_node(
+            "python",
+            source,
+            "(function_definition) @f",
+            Position::After,
+            "def g(self):\n    pass",
+        );
+        assert_eq!(
+            edited,
+            "class A:\n    def f(self):\n        pass\n    def g(self):\n        pass\n"
+        );
+    }
+
+    #[test]
+    fn prepend_to_body_inserts_before_the_first_statement() {
+        let edited = at_node(
+            "python",
+            "def f():\n    return 1\n",
+            "(function_definition body: (block) @b)",
+            Position::PrependToBody,
+            "x = 1",
+        );
+        assert_eq!(edited, "def f():\n    x = 1\n    return 1\n");
+    }
+
+    #[test]
+    fn prepend_to_body_splits_a_body_on_the_header_line() {
+        let edited = at_node(
+            "python",
+            "def f(): return 1\n",
+            "(function_definition body: (block) @b)",
+            Position::PrependToBody,
+            "x = 1",
+        );
+        assert_eq!(edited, "def f():\n    x = 1\n    return 1\n");
+
+        let edited = at_node(
+            "rust",
+            "fn f() { a(); }\n",
+            "(function_item body: (block) @b)",
+            Position::PrependToBody,
+            "b();",
+        );
+        assert_eq!(edited, "fn f() {\n    b();\n    a(); }\n");
+    }
+
+    #[test]
+    fn prepend_to_body_fills_an_empty_body() {
+        let edited = at_node(
+            "rust",
+            "fn f() {}\n",
+            "(function_item body: (block) @b)",
+            Position::PrependToBody,
+            "a();",
+        );
+        assert_eq!(edited, "fn f() {\n    a();\n}\n");
+    }
+
+    #[test]
+    fn append_to_body_inserts_after_the_last_statement() {
+        let edited = at_node(
+            "rust",
+            "fn f() {\n    a();\n}\n",
+            "(function_item body: (block) @b)",
+            Position::AppendToBody,
+            "b();",
+        );
+        assert_eq!(edited, "fn f() {\n    a();\n    b();\n}\n");
+    }
+
+    #[test]
+    fn replace_selection_indents_continuation_lines() {
+        let source = "def f():\n    pass\n";
+        let selection = Range::new(LspPosition::new(1, 4), LspPosition::new(1, 8));
+        let edited = place(
+            "python",
+            source,
+            "(block) @b",
+            Position::ReplaceSelection,
+            selection,
+            "a = 1\nb = 2",
+        );
+        assert_eq!(edited, "def f():\n    a = 1\n    b = 2\n");
+    }
+
+    #[test]
+    fn insert_at_cursor_keeps_the_rest_of_the_line() {
+        let source = "def f():\n    pass\n";
+        let cursor = Range::new(LspPosition::new(1, 4), LspPosition::new(1, 4));
+        let edited = place(
+            "python",
+            source,
+            "(block) @b",
+            Position::InsertAtCursor,
+            cursor,
+            "x = 1; ",
+        );
+        assert_eq!(edited, "def f():\n    x = 1; pass\n");
+    }
+
+    #[test]
+    fn end_of_file_starts_a_new_line() {
+        let query = "(function_definition) @f";
+        let edited = at_node(
+            "python",
+            "def f(): pass",
+            query,
+            Position::EndOfFile,
+            "x = 1",
+        );
+        assert_eq!(edited, "def f(): pass\nx = 1\n");
+        let edited = at_node(
+            "python",
+            "def f(): pass\n",
+            query,
+            Position::EndOfFile,
+            "x = 1\n",
+        );
+        assert_eq!(edited, "def f(): pass\nx = 1\n");
+    }
+
+    #[test]
+    fn new_file_ends_with_one_newline() {
+        let uri = Url::parse("file:///test").unwrap();
+        let doc = ParsedDocument::new("def f(): pass\n", &uri, "python");
+        let anchor = Anchor {
+            doc: &doc,
+            node: doc.tree.root_node(),
+            selection: Range::default(),
+        };
+        assert_eq!(
+            placement_range(&Position::NewFile, &anchor),
+            Range::default()
+        );
+        assert_eq!(
+            placement_text(&Position::NewFile, &anchor, "x = 1\n\n".to_owned()),
+            "x = 1\n"
+        );
+    }
+}
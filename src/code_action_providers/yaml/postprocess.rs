@@ -0,0 +1,250 @@
+//! The `postprocess` steps cleaning up an answer before it is placed.
+
+use anyhow::bail;
+use tower_lsp::lsp_types::Url;
+use tree_sitter::Node;
+
+use crate::code_action_providers::helper;
+use crate::code_action_providers::parsed_document::ParsedDocument;
+
+use super::config::PostprocessStep;
+
+/// The content of the first markdown code fence in `text`, or `text` if it has
+/// none.
+pub fn strip_fences(text: &str) -> String {
+    let mut lines = text
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("```"));
+    if lines.next().is_none() {
+        return text.to_owned();
+    }
+    let content: Vec<&str> = lines
+        .take_while(|line| !line.trim_start().starts_with("```"))
+        .collect();
+    content.join("\n")
+}
+
+/// The text between `<tag>` and `</tag>`, or `text` if there is no such block.
+fn extract_tag(text: &str, tag: &str) -> String {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let Some(start) = text.find(&open).map(|start| start + open.len()) else {
+        return text.to_owned();
+    };
+    let end = text[start..]
+        .find(&close)
+        .map_or(text.len(), |end| start + end);
+    text[start..end].trim_matches('\n').to_owned()
+}
+
+/// A line like "Here is the updated docstring:", which starts with an upper
+/// case letter, has several words and ends with a colon.
+fn is_prose(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with(|c: char| c.is_uppercase()) && line.contains(' ') && line.ends_with(':')
+}
+
+/// Drops leading prose lines and the blank lines following them.
+fn drop_leading_prose(text: &str) -> String {
+    let lines: Vec<&str> = text
+        .lines()
+        .skip_while(|line| line.trim().is_empty() || is_prose(line))
+        .collect();
+    lines.join("\n")
+}
+
+/// Removes the indentation common to all non-blank lines. Tabs and spaces
+/// only count as common where the lines agree on them.
+fn dedent(text: &str) -> String {
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .reduce(|common, indent| {
+            let len = common
+                .chars()
+                .zip(indent.chars())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a.len_utf8())
+                .sum();
+            &common[..len]
+        })
+        .unwrap_or_default();
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|line| line.strip_prefix(indent).unwrap_or(line.trim_start()))
+        .collect();
+    lines.join("\n")
+}
+
+/// The words of `text`, each with the whitespace before it.
+fn words(text: &str) -> Vec<(&str, &str)> {
+    let mut words = Vec::new();
+    let mut rest = text;
+    loop {
+        let start = rest.len() - rest.trim_start().len();
+        let word = &rest[start..];
+        let end = word.find(char::is_whitespace).unwrap_or(word.len());
+        if end == 0 {
+            return words;
+        }
+        words.push((&rest[..start], &word[..end]));
+        rest = &word[end..];
+    }
+}
+
+/// Breaks lines longer than `columns` characters at spaces, continuing with
+/// the indentation of the line. The spacing between words on a line is kept.
+fn wrap(text: &str, columns: usize) -> String {
+    let width = |text: &str| text.chars().count();
+    let mut wrapped = Vec::new();
+    for line in text.lines() {
+        if width(line) <= columns {
+            wrapped.push(line.to_owned());
+            continue;
+        }
+        let body = line.trim_start();
+        let indent = &line[..line.len() - body.len()];
+        let mut current = indent.to_owned();
+        let mut current_width = width(indent);
+        let mut empty = true;
+        for (space, word) in words(body) {
+            if !empty && current_width + width(space) + width(word) > columns {
+                wrapped.push(current);
+                current = indent.to_owned();
+                current_width = width(indent);
+                empty = true;
+            }
+            if !empty {
+                current.push_str(space);
+                current_width += width(space);
+            }
+            current.push_str(word);
+            current_width += width(word);
+            empty = false;
+        }
+        wrapped.push(current);
+    }
+    wrapped.join("\n")
+}
+
+/// The nodes spanning all of `text`, from the root down.
+fn spanning_nodes<'a>(root: Node<'a>, text: &str) -> Vec<Node<'a>> {
+    let start = text.len() - text.trim_start().len();
+    let end = text.trim_end().len();
+    let mut nodes = Vec::new();
+    let mut node = Some(root);
+    while let Some(current) = node {
+        nodes.push(current);
+        let mut cursor = current.walk();
+        node = current
+            .named_children(&mut cursor)
+            .find(|child| child.start_byte() <= start && end <= child.end_byte());
+    }
+    nodes
+}
+
+/// Checks that `text` parses without errors as a single `kind` node.
+fn validate(text: &str, kind: &str, language: &str, uri: &Url) -> anyhow::Result<()> {
+    let doc = ParsedDocument::new(text, uri, language);
+    let root = doc.tree.root_node();
+    if root.has_error() {
+        bail!("answer rejected, it does not parse as {language}");
+    }
+    if !spanning_nodes(root, text)
+        .iter()
+        .any(|node| node.kind() == kind)
+    {
+        bail!("answer rejected, it is not a single `{kind}`");
+    }
+    Ok(())
+}
+
+/// Runs `steps` in order on the answer to an action on the document `uri`.
+pub fn apply(
+    steps: &[PostprocessStep],
+    answer: String,
+    language: &str,
+    uri: &Url,
+) -> anyhow::Result<String> {
+    let mut answer = answer;
+    for step in steps {
+        answer = match step {
+            PostprocessStep::StripFences => strip_fences(&answer),
+            PostprocessStep::ExtractTag(tag) => extract_tag(&answer, tag),
+            PostprocessStep::DropLeadingProse => drop_leading_prose(&answer),
+            PostprocessStep::Dedent => dedent(&answer),
+            PostprocessStep::Reindent(width) => helper::indent_text(&dedent(&answer), *width),
+            PostprocessStep::Wrap(columns) => wrap(&answer, *columns),
+            PostprocessStep::Validate(kind) => {
+                validate(&answer, kind, language, uri)?;
+                answer
+            }
+        };
+    }
+    Ok(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_fences_keeps_the_first_fence() {
+        let answer = "Sure:\n```python\nx = 1\n```\n```\ny = 2\n```";
+        assert_eq!(strip_fences(answer), "x = 1");
+        assert_eq!(strip_fences("x = 1"), "x = 1");
+    }
+
+    #[test]
+    fn extract_tag_keeps_the_tagged_text() {
+        assert_eq!(extract_tag("a <code>\nx = 1\n</code> b", "code"), "x = 1");
+        assert_eq!(extract_tag("a <code>x = 1", "code"), "x = 1");
+        assert_eq!(extract_tag("x = 1", "code"), "x = 1");
+    }
+
+    #[test]
+    fn drop_leading_prose_keeps_the_answer() {
+        let answer = "Here is the docstring:\n\n\"\"\"Adds.\"\"\"\nNote: it adds:";
+        assert_eq!(
+            drop_leading_prose(answer),
+            "\"\"\"Adds.\"\"\"\nNote: it adds:"
+        );
+        assert_eq!(drop_leading_prose("Returns:"), "Returns:");
+    }
+
+    #[test]
+    fn dedent_removes_the_common_indentation() {
+        assert_eq!(dedent("    a\n\n      b\n    c"), "a\n\n  b\nc");
+        assert_eq!(dedent("\t\ta\n\tb"), "\ta\nb");
+    }
+
+    #[test]
+    fn dedent_keeps_tabs_and_spaces_that_differ() {
+        assert_eq!(dedent("\ta\n    b"), "\ta\n    b");
+        assert_eq!(dedent("\t  a\n\t    b"), "a\n  b");
+    }
+
+    #[test]
+    fn wrap_counts_characters() {
+        assert_eq!(wrap("  äöü äöü äöü", 10), "  äöü äöü\n  äöü");
+        assert_eq!(wrap("äöü äöü", 7), "äöü äöü");
+    }
+
+    #[test]
+    fn wrap_keeps_the_spacing_of_lines() {
+        assert_eq!(wrap("a  =  1", 5), "a  =\n1");
+        assert_eq!(wrap("x = 1  # short", 20), "x = 1  # short");
+        assert_eq!(wrap("# aaa  bbb ccc", 10), "# aaa  bbb\nccc");
+    }
+
+    #[test]
+    fn validate_accepts_a_single_node() {
+        let uri = Url::parse("file:///test.py").unwrap();
+        let function = "def f():\n    return 1\n";
+        assert!(validate(function, "function_definition", "python", &uri).is_ok());
+        assert!(validate("def f(:\n", "function_definition", "python", &uri).is_err());
+        let functions = "def f():\n    pass\ndef g():\n    pass\n";
+        assert!(validate(functions, "function_definition", "python", &uri).is_err());
+    }
+}
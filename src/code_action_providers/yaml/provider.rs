@@ -1,139 +1,391 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use minijinja::Value;
 use serde_json::json;
 use tower_lsp::jsonrpc::Error;
 use tower_lsp::jsonrpc::Result;
-use tower_lsp::lsp_types::{CodeAction, CodeActionKind, TextEdit, WorkspaceEdit};
+use tower_lsp::lsp_types::{
+    CodeAction, CodeActionKind, CreateFile, Diagnostic, DocumentChangeOperation, DocumentChanges,
+    OneOf, OptionalVersionedTextDocumentIdentifier, Range, ResourceOp, TextDocumentEdit, TextEdit,
+    Url, WorkspaceEdit,
+};
+use tree_sitter::Node;
 
+use crate::code_action_providers::candidates;
+use crate::code_action_providers::helper;
+use crate::code_action_providers::parsed_document::{ts_language, ParsedDocument, GRAMMARS};
+use crate::code_action_providers::syntax::{self, CandidateRequest};
 use crate::code_action_providers::traits::ActionContext;
 use crate::code_action_providers::traits::ActionProvider;
-use crate::code_action_providers::{helper, parsed_document::ParsedDocument};
+use crate::code_action_providers::traits::{ContextNode, PromptPreview};
 use crate::llm_handlers::traits::Llm;
+use crate::logging::audit;
 use crate::server::nop_codeaction;
 use crate::server::ResolveAction;
 
 use super::config;
-
-fn build_prompt(template: &str, hints: &HashMap<String, String>) -> String {
-    let mut prompt = template.to_owned();
-    for (name, hint) in hints {
-        prompt = prompt.replace(&format!("<<<{}>>>", name), hint);
-    }
-    prompt.to_owned()
-}
+use super::placement::{placement_range, placement_text, Anchor};
+use super::postprocess;
+use super::template;
 
 pub struct YamlProvider {
     prompt_handler: Arc<Llm>,
 
     config: config::CodeAction,
+    triggers: Vec<config::CompiledTrigger>,
+    /// The compiled query of each hint, `None` for hints without one.
+    hint_queries: Vec<Option<config::CompiledQuery>>,
+    /// The compiled query of each placement strategy, `None` for the context
+    /// node.
+    placement_queries: Vec<Option<config::CompiledQuery>>,
     id: String,
 }
 
 impl YamlProvider {
-    pub fn from_config(config: config::CodeAction, id: &str, prompt_handler: Arc<Llm>) -> Self {
-        Self {
+    /// Creates the provider of an action for `language`, failing if one of
+    /// its trigger, hint or placement queries does not compile. Actions for a
+    /// language without a grammar may run against documents of every grammar.
+    /// There a query only needs to be valid in one of them.
+    pub fn from_config(
+        config: config::CodeAction,
+        id: &str,
+        language: &str,
+        prompt_handler: Arc<Llm>,
+    ) -> anyhow::Result<Self> {
+        let grammars: Vec<_> = match ts_language(language) {
+            Some(grammar) => vec![grammar],
+            None => GRAMMARS
+                .iter()
+                .filter_map(|name| ts_language(name))
+                .collect(),
+        };
+        let compile = |what, query: &Option<String>| {
+            query
+                .as_deref()
+                .map(|query| config::CompiledQuery::new(what, query, &grammars))
+                .transpose()
+        };
+        let named = |e: anyhow::Error| anyhow::anyhow!("{}: {e}", config.name);
+        let triggers = config
+            .triggers
+            .iter()
+            .map(|trigger| trigger.compile(&grammars))
+            .collect::<anyhow::Result<_>>()
+            .map_err(named)?;
+        let hint_queries = config
+            .context
+            .hints
+            .iter()
+            .map(|hint| compile("hint", &hint.query))
+            .collect::<anyhow::Result<_>>()
+            .map_err(named)?;
+        let placement_queries = config
+            .placement_strategies
+            .iter()
+            .map(|placement| compile("placement", &placement.query))
+            .collect::<anyhow::Result<_>>()
+            .map_err(named)?;
+        Ok(Self {
             prompt_handler,
             config,
+            triggers,
+            hint_queries,
+            placement_queries,
             id: id.to_owned(),
+        })
+    }
+
+    /// The built-in template variables and the hints of `ctx_node`. Empty
+    /// hints, like those whose query matches nothing, are left undefined.
+    fn variables(
+        &self,
+        doc: &ParsedDocument,
+        ctx_node: &Node,
+        selection: Option<&Range>,
+        diagnostics: &[Diagnostic],
+    ) -> BTreeMap<String, Value> {
+        let mut variables = template::builtins(
+            doc,
+            &helper::ts_node_to_lsp_range(ctx_node),
+            selection,
+            diagnostics,
+        );
+        for (hint, query) in self.config.context.hints.iter().zip(&self.hint_queries) {
+            let query = query.as_ref().and_then(|query| query.get(doc));
+            if let Some(value) = hint.value(doc, ctx_node, selection, query) {
+                log::debug!("hint {} of {}: {}", hint.name, self.id, value);
+                variables.insert(hint.name.clone(), value);
+            }
+        }
+        variables
+    }
+
+    fn render(&self, template: &str, variables: &BTreeMap<String, Value>) -> Result<String> {
+        template::render(template, variables)
+            .map_err(|e| helper::internal_error(format!("{}: {e}", self.config.name)))
+    }
+
+    /// Returns the first placement strategy whose query matches below `ctx_node`.
+    fn find_placement<'a>(
+        &self,
+        doc: &'a ParsedDocument,
+        ctx_node: &'a Node,
+    ) -> Option<(&config::PlacementStrategy, Node<'a>)> {
+        self.config
+            .placement_strategies
+            .iter()
+            .zip(&self.placement_queries)
+            .find_map(|(placement, query)| match query {
+                Some(query) => doc
+                    .first_capture(ctx_node, query.get(doc)?)
+                    .map(|node| (placement, node)),
+                None => Some((placement, *ctx_node)),
+            })
+    }
+
+    /// The title of the candidate `answer` and where it goes, `None` if no
+    /// placement matches.
+    fn place(
+        &self,
+        doc: &ParsedDocument,
+        args: &ActionContext,
+        placement: Option<(&config::PlacementStrategy, Node)>,
+        variables: &mut BTreeMap<String, Value>,
+        answer: &str,
+    ) -> Result<Option<(String, Placed)>> {
+        let mut answer = postprocess::apply(
+            &self.config.postprocess,
+            answer.to_owned(),
+            doc.language(),
+            &doc.uri,
+        )
+        .map_err(|e| helper::internal_error(format!("{}: {e}", self.config.name)))?;
+        variables.insert("ANSWER".to_owned(), Value::from(answer.clone()));
+        let title = match &self.config.candidate_title {
+            Some(title) => self.render(title, variables)?.trim().to_owned(),
+            None => candidates::default_title(&answer),
+        };
+        if let Some(answer_template) = &self.config.answer_template {
+            answer = self.render(answer_template, variables)?;
+        }
+
+        let Some((placement, placement_node)) = placement else {
+            return Ok(None);
+        };
+        log::debug!("placement of {}: {:?}", self.id, placement);
+        let anchor = Anchor {
+            doc,
+            node: placement_node,
+            selection: args.selection.unwrap_or(args.selection_range),
+        };
+        let range = placement_range(&placement.position, &anchor);
+        let new_text = placement_text(&placement.position, &anchor, answer);
+        let placed = match placement.position {
+            config::Position::NewFile => {
+                Placed::NewFile(self.new_file_uri(doc, placement, variables)?, new_text)
+            }
+            _ => Placed::Edit(TextEdit { range, new_text }),
+        };
+        Ok(Some((title, placed)))
+    }
+
+    /// The file a `new_file` placement creates, next to `doc` unless the
+    /// rendered path is absolute.
+    fn new_file_uri(
+        &self,
+        doc: &ParsedDocument,
+        placement: &config::PlacementStrategy,
+        variables: &BTreeMap<String, Value>,
+    ) -> Result<Url> {
+        let template = placement.path.as_deref().unwrap_or_default();
+        let path = PathBuf::from(self.render(template, variables)?.trim());
+        let path = match doc.uri.to_file_path() {
+            Ok(doc_path) if path.is_relative() => {
+                doc_path.parent().map(|dir| dir.join(&path)).unwrap_or(path)
+            }
+            _ => path,
+        };
+        if path.exists() {
+            return Err(helper::internal_error(format!(
+                "{}: {} exists already",
+                self.config.name,
+                path.display()
+            )));
+        }
+        Url::from_file_path(&path).map_err(|_| {
+            helper::internal_error(format!(
+                "{}: invalid file path {}",
+                self.config.name,
+                path.display()
+            ))
+        })
+    }
+}
+
+/// Where a candidate answer goes.
+enum Placed {
+    Edit(TextEdit),
+    NewFile(Url, String),
+}
+
+impl Placed {
+    fn new_text(&self) -> &str {
+        match self {
+            Placed::Edit(edit) => &edit.new_text,
+            Placed::NewFile(_, text) => text,
         }
     }
+
+    fn into_edit(self, uri: &Url) -> WorkspaceEdit {
+        match self {
+            Placed::Edit(edit) => WorkspaceEdit {
+                changes: Some([(uri.clone(), vec![edit])].into_iter().collect()),
+                ..Default::default()
+            },
+            Placed::NewFile(uri, text) => new_file_edit(uri, text),
+        }
+    }
+}
+
+/// An edit creating the file `uri` with the content `text`.
+fn new_file_edit(uri: Url, text: String) -> WorkspaceEdit {
+    WorkspaceEdit {
+        document_changes: Some(DocumentChanges::Operations(vec![
+            DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                uri: uri.clone(),
+                options: None,
+                annotation_id: None,
+            })),
+            DocumentChangeOperation::Edit(TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier { uri, version: None },
+                edits: vec![OneOf::Left(TextEdit {
+                    range: Range::default(),
+                    new_text: text,
+                })],
+            }),
+        ])),
+        ..Default::default()
+    }
 }
+
 #[async_trait]
 impl ActionProvider for YamlProvider {
+    fn action_name(&self) -> String {
+        self.config.name.clone()
+    }
     fn can_handle(&self, action_name: &str) -> bool {
         action_name == self.id
     }
     async fn on_resolve(&self, doc: &ParsedDocument, action: CodeAction) -> Result<CodeAction> {
+        let actions = self.on_resolve_candidates(doc, action).await?;
+        Ok(actions.into_iter().next().unwrap_or_else(nop_codeaction))
+    }
+    async fn on_resolve_candidates(
+        &self,
+        doc: &ParsedDocument,
+        action: CodeAction,
+    ) -> Result<Vec<CodeAction>> {
         let args =
             serde_json::from_value::<ResolveAction<ActionContext>>(action.data.clone().unwrap())
                 .unwrap()
                 .data;
 
-        let ctx_node = doc.get_ts_node_for_range(&args.selection_range);
-
-        if let Some(ctx_node) = ctx_node {
-            let mut hint_texts: HashMap<String, String> = Default::default();
-
-            for hint in self.config.context.hints.iter() {
-                let hint_node = doc.find_first(&ctx_node, &hint.query);
-                if let Some(hint_node) = hint_node {
-                    let hint_text = doc.text_from_node(&hint_node);
-                    hint_texts.insert(hint.name.clone(), hint_text);
-                }
-                // let function_text = doc.text_from_node(&function_node);
+        let Some(ctx_node) = doc.get_ts_node_for_range(&args.selection_range) else {
+            return Ok(Vec::new());
+        };
+        let mut variables =
+            self.variables(doc, &ctx_node, args.selection.as_ref(), &args.diagnostics);
+        let prompt = self.render(&self.config.prompt_template, &variables)?;
+        let placement = self.find_placement(doc, &ctx_node);
+        let prompt_handler = if self.config.cache == Some(false) {
+            self.prompt_handler.uncached()
+        } else {
+            &self.prompt_handler
+        };
+        let count = self.config.candidates.unwrap_or(1);
+        let request = CandidateRequest {
+            llm: prompt_handler,
+            action: &self.config.name,
+            id: &self.id,
+            count,
+            source: self.config.candidate_source,
+            check: self.config.validate_syntax,
+        };
+        syntax::resolve_candidates(request, doc, &prompt, |prompt, completion, candidate| {
+            let placed = self.place(doc, &args, placement, &mut variables, candidate);
+            audit(
+                &self.id,
+                &args.uri,
+                prompt,
+                &completion.answer,
+                placed
+                    .as_ref()
+                    .ok()
+                    .and_then(|placed| placed.as_ref())
+                    .map(|(_, placed)| placed.new_text()),
+                completion.latency,
+            );
+            let Some((title, placed)) = placed? else {
+                log::info!("no placement of {} matches, discarding the answer", self.id);
+                return Ok(None);
+            };
+            let mut action = action.clone();
+            if count > 1 {
+                action.title = title;
             }
-            //log::info!("hints {:?}", hint_texts);
+            action.edit = Some(placed.into_edit(&args.uri));
+            Ok(Some(action))
+        })
+        .await
+    }
+    fn preview(&self, doc: &ParsedDocument, action: &CodeAction) -> Result<PromptPreview> {
+        let args = serde_json::from_value::<ResolveAction<ActionContext>>(
+            action.data.clone().ok_or(Error::invalid_request())?,
+        )
+        .map_err(|_| Error::invalid_request())?
+        .data;
 
-            let prompt = build_prompt(&self.config.prompt_template, &hint_texts);
-            //log::info!("prompt {}", prompt);
-            let mut answer = self.prompt_handler.answer(&prompt).await.unwrap();
-            if let Some(answer_template) = self.config.answer_template.clone() {
-                answer = answer_template.replace("<<<ANSWER>>>", &answer);
-            }
-            //log::info!("answer {}", answer);
-
-            for placement in self.config.placement_strategies.iter() {
-                let placement_node = doc.find_first(&ctx_node, &placement.query);
-                if let Some(placement_node) = placement_node {
-                    //log::info!("placement {:?}", placement);
-                    let (range, new_text) = match placement.position {
-                        config::Position::ReplaceBlock => {
-                            let mut placement_range = helper::ts_node_to_lsp_range(&placement_node);
-                            placement_range.start.character = 0;
-                            let new_text = helper::indent_text(
-                                &answer,
-                                placement_node.range().start_point.column,
-                            );
-                            (placement_range, new_text)
-                        }
-                        config::Position::ReplaceExact => {
-                            let placement_range = helper::ts_node_to_lsp_range(&placement_node);
-                            (placement_range, answer)
-                        }
-                        config::Position::Before => {
-                            let placement_range =
-                                helper::prepend_ts_node_to_lsp_range(&placement_node);
-                            let new_text = format!(
-                                "{}\n",
-                                helper::indent_text(
-                                    &answer,
-                                    placement_node.range().start_point.column,
-                                )
-                            );
-                            (placement_range, new_text)
-                        }
-                    };
-
-                    //log::info!("new_text {:?}", new_text);
-                    let text_edit = TextEdit { range, new_text };
-                    let mut action = action.clone();
-                    action.edit = Some(WorkspaceEdit {
-                        changes: Some([(args.uri.clone(), vec![text_edit])].into_iter().collect()),
-                        ..Default::default()
-                    });
-
-                    return Ok(action);
-                }
-            }
-        }
-        return Ok(nop_codeaction());
+        let ctx_node = doc
+            .get_ts_node_for_range(&args.selection_range)
+            .ok_or(Error::request_cancelled())?;
+        let placement_range = self
+            .find_placement(doc, &ctx_node)
+            .map(|(placement, node)| {
+                let anchor = Anchor {
+                    doc,
+                    node,
+                    selection: args.selection.unwrap_or(args.selection_range),
+                };
+                placement_range(&placement.position, &anchor)
+            });
+        Ok(PromptPreview {
+            action: self.config.name.clone(),
+            prompt: self.render(
+                &self.config.prompt_template,
+                &self.variables(doc, &ctx_node, args.selection.as_ref(), &args.diagnostics),
+            )?,
+            placement_range,
+            context_node: Some(ContextNode {
+                kind: ctx_node.kind().to_owned(),
+                range: helper::ts_node_to_lsp_range(&ctx_node),
+            }),
+        })
     }
     fn create_code_action(
         &self,
         doc: &ParsedDocument,
         start_range: &tower_lsp::lsp_types::Range,
+        diagnostics: &[Diagnostic],
     ) -> Option<tower_lsp::lsp_types::CodeAction> {
         let cursor_node = doc.get_ts_node_for_range(start_range);
-        //log::info!("Cursor node {:?}", cursor_node);
 
         let is_triggered = self
-            .config
             .triggers
             .iter()
-            .any(|trigger| trigger.is_triggered(cursor_node));
-        //log::info!("is_triggered {:?}", is_triggered);
+            .any(|trigger| trigger.is_triggered(doc, start_range));
 
         if !is_triggered {
             return None;
@@ -149,7 +401,9 @@ impl ActionProvider for YamlProvider {
                     id: self.id.to_string(),
                     data: ActionContext {
                         uri: doc.uri.to_owned(),
-                        selection_range
+                        selection_range,
+                        selection: Some(start_range.to_owned()),
+                        diagnostics: diagnostics.to_vec(),
                     }
                 })),
                 ..Default::default()
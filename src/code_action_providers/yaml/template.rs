@@ -0,0 +1,259 @@
+//! Rendering of `prompt_template` and `answer_template` with minijinja.
+//!
+//! Besides the hints, templates can use the built-in variables of `builtins`,
+//! see the YAML section of TUTORIAL.md.
+//!
+//! The old `<<<NAME>>>` placeholders are still understood as `{{ NAME }}`.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use anyhow::anyhow;
+use minijinja::value::{Enumerator, Object, ObjectRepr};
+use minijinja::{Environment, ErrorKind, UndefinedBehavior, Value};
+use regex::Regex;
+use tower_lsp::lsp_types::{Diagnostic, Range};
+
+use crate::code_action_providers::diagnostics;
+use crate::code_action_providers::parsed_document::ParsedDocument;
+
+/// Number of lines in `lines_before` and `lines_after`.
+const SURROUNDING_LINES: usize = 10;
+
+/// The captures of a `mode: all` hint. Templates can iterate over them or
+/// index them, printing the hint joins them with `separator`.
+#[derive(Debug)]
+pub struct Captures {
+    pub texts: Vec<String>,
+    pub separator: String,
+}
+
+impl Object for Captures {
+    fn repr(self: &Arc<Self>) -> ObjectRepr {
+        ObjectRepr::Seq
+    }
+
+    fn get_value(self: &Arc<Self>, key: &Value) -> Option<Value> {
+        let text = self.texts.get(key.as_usize()?)?;
+        Some(Value::from(text.as_str()))
+    }
+
+    fn enumerate(self: &Arc<Self>) -> Enumerator {
+        Enumerator::Seq(self.texts.len())
+    }
+
+    fn render(self: &Arc<Self>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.texts.join(&self.separator))
+    }
+}
+
+/// Rewrites `<<<NAME>>>` placeholders to `{{ NAME }}`.
+fn legacy_syntax(template: &str) -> Cow<'_, str> {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    PLACEHOLDER
+        .get_or_init(|| Regex::new(r"<<<(\w+)>>>").expect("valid regex"))
+        .replace_all(template, "{{ $1 }}")
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    // Printing a missing variable fails, testing it with `if` does not.
+    env.set_undefined_behavior(UndefinedBehavior::SemiStrict);
+    env.set_keep_trailing_newline(true);
+    env
+}
+
+/// Renders `template` with `variables`.
+pub fn render(template: &str, variables: &BTreeMap<String, Value>) -> anyhow::Result<String> {
+    let env = environment();
+    let source = legacy_syntax(template);
+    let template = env.template_from_str(&source).map_err(template_error)?;
+    template.render(variables).map_err(|e| {
+        if e.kind() != ErrorKind::UndefinedError {
+            return template_error(e);
+        }
+        let mut missing: Vec<String> = template
+            .undeclared_variables(false)
+            .into_iter()
+            .filter(|name| !variables.contains_key(name))
+            .collect();
+        missing.sort();
+        anyhow!("{}, not defined: {}", template_error(e), missing.join(", "))
+    })
+}
+
+fn template_error(e: minijinja::Error) -> anyhow::Error {
+    let message = match e.detail() {
+        Some(detail) => format!("{}: {detail}", e.kind()),
+        None => e.kind().to_string(),
+    };
+    match e.line() {
+        Some(line) => anyhow!("template line {line}: {message}"),
+        None => anyhow!("template: {message}"),
+    }
+}
+
+/// The 1-based line of the first `{{`, `{%` or `{#` in a template that also
+/// uses `<<<NAME>>>` placeholders. Before minijinja such text was printed as
+/// is, now it is rendered.
+fn legacy_markup_line(template: &str) -> Option<usize> {
+    if !template.contains("<<<") || legacy_syntax(template) == template {
+        return None;
+    }
+    let markup = ["{{", "{%", "{#"]
+        .iter()
+        .filter_map(|markup| template.find(markup))
+        .min()?;
+    Some(template[..markup].matches('\n').count() + 1)
+}
+
+/// Checks the syntax of `template`, returning the 1-based line of an error.
+pub fn validate(template: &str) -> Result<(), (usize, String)> {
+    let env = environment();
+    let source = legacy_syntax(template);
+    if let Err(e) = env.template_from_str(&source) {
+        return Err((e.line().unwrap_or(1), e.to_string()));
+    }
+    match legacy_markup_line(template) {
+        Some(line) => Err((
+            line,
+            "`{{`, `{%` and `{#` are template syntax, not plain text, in a template \
+             with `<<<NAME>>>` placeholders; use `{{ NAME }}` and wrap literal text \
+             in `{% raw %}`"
+                .to_owned(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Up to `count` lines of `source` before and after the lines
+/// `start_line..=end_line`.
+pub fn lines_around(
+    source: &str,
+    start_line: usize,
+    end_line: usize,
+    count: usize,
+) -> (String, String) {
+    let lines: Vec<&str> = source.lines().collect();
+    let before_end = start_line.min(lines.len());
+    let before = lines[before_end.saturating_sub(count)..before_end].join("\n");
+    let after_start = (end_line + 1).min(lines.len());
+    let after = lines[after_start..(after_start + count).min(lines.len())].join("\n");
+    (before, after)
+}
+
+/// The variables every template of an action on `context_range` can use.
+pub fn builtins(
+    doc: &ParsedDocument,
+    context_range: &Range,
+    selection: Option<&Range>,
+    diagnostics: &[Diagnostic],
+) -> BTreeMap<String, Value> {
+    let path = doc.uri.to_file_path().ok();
+    let file_path = path
+        .as_ref()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| doc.uri.to_string());
+    let file_name = |name: Option<&std::ffi::OsStr>| {
+        name.map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    let (lines_before, lines_after) = lines_around(
+        doc.source(),
+        context_range.start.line as usize,
+        context_range.end.line as usize,
+        SURROUNDING_LINES,
+    );
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default();
+    let variables = [
+        ("file_path", file_path),
+        (
+            "file_name",
+            file_name(path.as_ref().and_then(|path| path.file_name())),
+        ),
+        (
+            "file_stem",
+            file_name(path.as_ref().and_then(|path| path.file_stem())),
+        ),
+        ("language", doc.language().to_owned()),
+        (
+            "selection",
+            selection
+                .map(|range| doc.text_from_range(range))
+                .unwrap_or_default(),
+        ),
+        ("lines_before", lines_before),
+        ("lines_after", lines_after),
+        ("diagnostics", diagnostics::describe(diagnostics)),
+        ("user", user),
+        ("date", chrono::Local::now().format("%Y-%m-%d").to_string()),
+    ];
+    variables
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), Value::from(value)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tower_lsp::lsp_types::Url;
+    use tree_sitter::Query;
+
+    use super::*;
+    use crate::code_action_providers::yaml::config::Hint;
+
+    const SOURCE: &str = "class A:\n    def first(self): pass\n\n    def second(self): pass\n";
+
+    /// Renders `template` with the hint `hint`, written in YAML, of the class
+    /// in `SOURCE`.
+    fn render_hint(hint: &str, template: &str) -> String {
+        let uri = Url::parse("file:///test.py").unwrap();
+        let doc = ParsedDocument::new(SOURCE, &uri, "python");
+        let hint: Hint = serde_yaml::from_str(hint).unwrap();
+        let query = Query::new(&doc.tree.language(), hint.query.as_deref().unwrap()).unwrap();
+        let root = doc.tree.root_node();
+        let value = hint.value(&doc, &root, None, Some(&query)).unwrap();
+        let variables = BTreeMap::from([(hint.name, value)]);
+        render(template, &variables).unwrap()
+    }
+
+    const NAMES: &str = "
+        name: NAMES
+        query: '(function_definition name: (identifier) @name)'
+        mode: all
+        separator: ', '
+    ";
+
+    #[test]
+    fn loops_over_all_captures() {
+        let rendered = render_hint(NAMES, "{% for name in NAMES %}- {{ name }}\n{% endfor %}");
+        assert_eq!(rendered, "- first\n- second\n");
+    }
+
+    #[test]
+    fn indexes_and_counts_captures() {
+        let rendered = render_hint(NAMES, "{{ NAMES | length }} {{ NAMES[1] }}");
+        assert_eq!(rendered, "2 second");
+    }
+
+    #[test]
+    fn prints_captures_with_separator() {
+        assert_eq!(render_hint(NAMES, "{{ NAMES }}"), "first, second");
+        assert_eq!(
+            render_hint(NAMES, "{{ NAMES | join(' ') }}"),
+            "first second"
+        );
+        assert_eq!(render_hint(NAMES, "{{ NAMES | upper }}"), "FIRST, SECOND");
+    }
+
+    #[test]
+    fn cuts_captures_to_max_length() {
+        let hint = format!("{NAMES}    max_length: 9\n");
+        assert_eq!(render_hint(&hint, "{{ NAMES | length }}"), "2");
+        assert_eq!(render_hint(&hint, "{{ NAMES }}"), "first, se");
+    }
+}
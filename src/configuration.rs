@@ -1,25 +1,351 @@
 use serde::{Deserialize, Serialize};
 
 use std::{
+    collections::HashMap,
     env, fs,
     path::{Path, PathBuf},
 };
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Where polyglot_ls looks for its configuration, actions and logs.
+///
+/// Everything lives below a single root directory, which is resolved from (in
+/// order) the `--config-dir` flag, the directory of `--polyglot-config-path`,
+/// `$POLYGLOT_LS_CONFIG_DIR`, `$XDG_CONFIG_HOME/polyglot_ls` and finally
+/// `$HOME/.config/polyglot_ls`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigLayout {
+    /// The config root directory.
+    pub root: PathBuf,
+    /// The server configuration YAML file.
+    pub server_config: PathBuf,
+    /// The per-language code action directories.
+    pub code_actions_dir: PathBuf,
+    /// Log files.
+    pub log_dir: PathBuf,
+    /// Token usage per action, model and day.
+    pub usage_file: PathBuf,
+    /// Cached model answers.
+    pub cache_dir: PathBuf,
+}
+
+impl ConfigLayout {
+    /// Environment variable overriding the config root.
+    pub const ENV_VAR: &'static str = "POLYGLOT_LS_CONFIG_DIR";
+
+    /// Resolves the layout from the command line arguments and the environment.
+    pub fn resolve(config_dir: Option<&Path>, server_config: Option<&Path>) -> Self {
+        let root = config_dir
+            .map(Path::to_path_buf)
+            .or_else(|| {
+                server_config
+                    .and_then(Path::parent)
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                    .map(Path::to_path_buf)
+            })
+            .or_else(|| env::var_os(Self::ENV_VAR).map(PathBuf::from))
+            .or_else(|| {
+                env::var_os("XDG_CONFIG_HOME").map(|dir| PathBuf::from(dir).join("polyglot_ls"))
+            })
+            .unwrap_or_else(|| {
+                let home_dir = env::var("HOME").unwrap_or_else(|_| ".".to_string());
+                PathBuf::from(home_dir).join(".config").join("polyglot_ls")
+            });
+
+        let mut layout = Self::from_root(root);
+        if let Some(server_config) = server_config {
+            layout.server_config = server_config.to_path_buf();
+        }
+        layout
+    }
+
+    /// Creates the default layout below `root`.
+    pub fn from_root(root: PathBuf) -> Self {
+        Self {
+            server_config: root.join("server_config.yaml"),
+            code_actions_dir: root.join("code_actions"),
+            log_dir: root.join("logs"),
+            usage_file: root.join("usage.json"),
+            cache_dir: root.join("cache"),
+            root,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let entries = [
+            ("config root", &self.root),
+            ("server config", &self.server_config),
+            ("code actions", &self.code_actions_dir),
+            ("logs", &self.log_dir),
+            ("usage", &self.usage_file),
+            ("cache", &self.cache_dir),
+        ];
+        for (name, path) in entries {
+            let status = if path.exists() { "" } else { " (missing)" };
+            writeln!(f, "{:<14} {}{}", format!("{name}:"), path.display(), status)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct PolyglotConfig {
     pub model: ModelConfig,
+    /// Workspace folders whose `.polyglot_ls` code actions may be loaded.
+    #[serde(default)]
+    pub trusted_projects: Vec<PathBuf>,
+    /// Languages code actions are offered for. All languages if unset.
+    #[serde(default)]
+    pub enabled_languages: Option<Vec<String>>,
+    /// Names of code actions that should never be offered.
+    #[serde(default)]
+    pub disabled_actions: Vec<String>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub usage: UsageConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub preview: PreviewConfig,
+    #[serde(default)]
+    pub review: ReviewConfig,
+}
+
+/// Where and how much the server logs.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// One of `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub level: String,
+    /// The log file, `<config root>/logs/polyglot_ls.log` if unset.
+    pub file: Option<PathBuf>,
+    /// Size in megabytes after which a log file is rotated.
+    pub max_size_mb: u64,
+    /// Number of rotated log files to keep.
+    pub max_files: u32,
+    /// Record every resolved action with prompt, answers, latency and tokens.
+    pub audit: bool,
+    /// The audit log, `<config root>/logs/audit.jsonl` if unset.
+    pub audit_file: Option<PathBuf>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            file: None,
+            max_size_mb: 10,
+            max_files: 5,
+            audit: false,
+            audit_file: None,
+        }
+    }
+}
+
+/// Token budgets and prices used by the usage accounting.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct UsageConfig {
+    /// Record the tokens spent with Bedrock models to `usage.json`.
+    pub enabled: bool,
+    /// Tokens that may be spent per day, unlimited if unset.
+    pub daily_token_budget: Option<u64>,
+    /// What to do once the daily budget is used up.
+    pub on_budget_exceeded: BudgetAction,
+    /// Dollars per million tokens, keyed by model id.
+    pub prices: HashMap<String, ModelPrice>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetAction {
+    /// Keep answering but warn once per day.
+    #[default]
+    Warn,
+    /// Refuse to ask the model until the next day.
+    Refuse,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelPrice {
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+/// The on-disk cache of model answers.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Answer repeated prompts from the cache instead of asking the model.
+    pub enabled: bool,
+    /// Hours after which a cached answer is asked for again.
+    pub ttl_hours: u64,
+    /// Size in megabytes above which the oldest answers are evicted.
+    pub max_size_mb: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_hours: 24 * 7,
+            max_size_mb: 50,
+        }
+    }
+}
+
+/// Reviewing the diff of an action before its edit is applied.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct PreviewConfig {
+    /// Show a diff and wait for `polyglot.applyPreview` instead of editing.
+    pub enabled: bool,
+    /// Names of the actions to preview, all actions if empty.
+    pub actions: Vec<String>,
+}
+
+/// The review of functions by the model, published as diagnostics.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct ReviewConfig {
+    /// Review the changed functions of a document whenever it is saved.
+    pub on_save: bool,
+    /// Saves within this many milliseconds of each other are reviewed once.
+    pub debounce_ms: u64,
+    /// Functions sent to the model per review at most.
+    pub max_functions: usize,
+    /// Rules checked in every language.
+    pub rules: Vec<String>,
+    /// Function queries and rules, keyed by language id.
+    pub languages: HashMap<String, ReviewLanguageConfig>,
+}
+
+impl Default for ReviewConfig {
+    fn default() -> Self {
+        Self {
+            on_save: false,
+            debounce_ms: 2000,
+            max_functions: 20,
+            rules: Vec::new(),
+            languages: HashMap::new(),
+        }
+    }
+}
+
+impl ReviewConfig {
+    /// The rules checked in `language`, the general ones first.
+    pub fn rules(&self, language: &str) -> Vec<String> {
+        let mut rules = self.rules.clone();
+        if let Some(config) = self.languages.get(language) {
+            rules.extend(config.rules.iter().cloned());
+        }
+        rules
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct ReviewLanguageConfig {
+    /// A tree-sitter query capturing the functions to review, a built-in one
+    /// if unset.
+    pub query: Option<String>,
+    /// Rules checked in this language only.
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct ModelConfig {
     pub bedrock: BedrockConfig,
+    /// Models asked in this order when `bedrock` keeps failing.
+    #[serde(default)]
+    pub fallbacks: Vec<BedrockConfig>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+/// How often and how patiently a model is asked before falling back.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Retries of a request failing with a throttling, timeout or server error.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every further one.
+    pub initial_backoff_ms: u64,
+    /// Upper limit of the wait between retries.
+    pub max_backoff_ms: u64,
+    /// Time after which a single attempt is abandoned.
+    pub attempt_timeout_secs: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 8000,
+            attempt_timeout_secs: 120,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct BedrockConfig {
     pub model_id: String,
     pub region: String,
     pub aws_profile: String,
+    /// Sampling temperature, the model's default if unset.
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Maximum number of tokens in an answer, the model's default if unset.
+    #[serde(default)]
+    pub max_tokens: Option<i32>,
+}
+
+/// Settings sent by the editor through `initializationOptions` or
+/// `workspace/didChangeConfiguration`.
+///
+/// Every field that is set overrides the corresponding server config value.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ClientSettings {
+    pub model_id: Option<String>,
+    pub region: Option<String>,
+    pub aws_profile: Option<String>,
+    /// Overrides `model.retry.attempt_timeout_secs`.
+    pub timeout_secs: Option<u64>,
+    pub enabled_languages: Option<Vec<String>>,
+    pub disabled_actions: Option<Vec<String>>,
+    pub preview: Option<bool>,
+    pub review_on_save: Option<bool>,
+}
+
+impl ClientSettings {
+    /// Parses the settings from a JSON value sent by the client.
+    ///
+    /// Settings may either be passed directly or nested under a `polyglot_ls`
+    /// key, which is how most editors scope settings per server.
+    pub fn from_json(value: serde_json::Value) -> anyhow::Result<Self> {
+        let value = match value {
+            serde_json::Value::Object(mut map) if map.contains_key("polyglot_ls") => {
+                map.remove("polyglot_ls").unwrap()
+            }
+            serde_json::Value::Null => return Ok(Self::default()),
+            value => value,
+        };
+        Ok(serde_json::from_value(value)?)
+    }
 }
 
 impl Default for PolyglotConfig {
@@ -30,18 +356,25 @@ impl Default for PolyglotConfig {
                     model_id: "anthropic.claude-3-haiku-20240307-v1:0".to_string(),
                     region: "us-east-1".to_string(),
                     aws_profile: "my-aws-bedrock".to_string(),
+                    temperature: None,
+                    max_tokens: None,
                 },
+                fallbacks: Vec::new(),
+                retry: RetryConfig::default(),
             },
+            trusted_projects: Vec::new(),
+            enabled_languages: None,
+            disabled_actions: Vec::new(),
+            logging: LoggingConfig::default(),
+            usage: UsageConfig::default(),
+            cache: CacheConfig::default(),
+            preview: PreviewConfig::default(),
+            review: ReviewConfig::default(),
         }
     }
 }
 
 impl PolyglotConfig {
-    pub fn default_file_path() -> PathBuf {
-        let home_dir = env::var("HOME").unwrap_or_else(|_| ".".to_string());
-        PathBuf::from(home_dir).join(".config/polyglot_ls.yaml")
-    }
-
     pub fn try_read_from_file<P: AsRef<Path>>(config_path: P) -> anyhow::Result<Self> {
         if config_path.as_ref().exists() {
             match fs::read_to_string(&config_path) {
@@ -54,4 +387,73 @@ impl PolyglotConfig {
         }
         anyhow::bail!("path does not exists")
     }
+
+    /// Overrides the config with the settings sent by the client.
+    pub fn apply(&mut self, settings: ClientSettings) {
+        let bedrock = &mut self.model.bedrock;
+        if let Some(model_id) = settings.model_id {
+            bedrock.model_id = model_id;
+        }
+        if let Some(region) = settings.region {
+            bedrock.region = region;
+        }
+        if let Some(aws_profile) = settings.aws_profile {
+            bedrock.aws_profile = aws_profile;
+        }
+        if let Some(timeout_secs) = settings.timeout_secs {
+            self.model.retry.attempt_timeout_secs = timeout_secs;
+        }
+        if settings.enabled_languages.is_some() {
+            self.enabled_languages = settings.enabled_languages;
+        }
+        if let Some(disabled_actions) = settings.disabled_actions {
+            self.disabled_actions = disabled_actions;
+        }
+        if let Some(preview) = settings.preview {
+            self.preview.enabled = preview;
+        }
+        if let Some(on_save) = settings.review_on_save {
+            self.review.on_save = on_save;
+        }
+    }
+
+    /// Returns true if code actions should be offered for `language`.
+    pub fn is_language_enabled(&self, language: &str) -> bool {
+        self.enabled_languages
+            .as_ref()
+            .map(|languages| languages.iter().any(|l| l == language))
+            .unwrap_or(true)
+    }
+
+    /// Returns true if the user allowed loading code actions from `project_dir`.
+    pub fn is_trusted_project(&self, project_dir: &Path) -> bool {
+        let project_dir = project_dir
+            .canonicalize()
+            .unwrap_or_else(|_| project_dir.to_path_buf());
+        self.trusted_projects.iter().any(|trusted| {
+            trusted
+                .canonicalize()
+                .map(|trusted| trusted == project_dir)
+                .unwrap_or(trusted == &project_dir)
+        })
+    }
+}
+
+/// Per-project settings stored in `<workspace>/.polyglot_ls/config.yaml`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// Names of code actions that should not be offered in this project.
+    #[serde(default)]
+    pub disabled_actions: Vec<String>,
+}
+
+impl ProjectConfig {
+    /// The directory inside a workspace folder that holds project settings.
+    pub const DIR_NAME: &'static str = ".polyglot_ls";
+
+    pub fn try_read_from_file<P: AsRef<Path>>(config_path: P) -> anyhow::Result<Self> {
+        let config_data = fs::read_to_string(config_path)?;
+        Ok(serde_yaml::from_str(&config_data)?)
+    }
 }
@@ -0,0 +1,43 @@
+//! An LLM-based language server whose code actions are configured in Lua or
+//! YAML and get their context from tree-sitter.
+
+use std::path::{Path, PathBuf};
+
+pub mod apply;
+pub mod chat;
+pub mod check;
+pub mod code_action_providers;
+pub mod configuration;
+pub mod llm_handlers;
+pub mod logging;
+pub mod nonsense;
+pub mod preview;
+pub mod review;
+pub mod server;
+pub mod usage;
+
+/// Reads all language configuration files in the specified directory that
+/// match the given filter.
+///
+/// # Arguments
+///
+/// * `config_dir` - The directory containing the configuration files.
+/// * `filter` - The file extension filter to apply.
+///
+/// # Returns
+///
+/// A vector of `PathBuf` containing the paths of the matching configuration files.
+pub fn read_language_config_files(config_dir: &Path, filter: &str) -> Vec<PathBuf> {
+    let mut config_files = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(config_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if (path.is_file() || path.is_symlink())
+                && path.extension().map(|ext| ext == filter).unwrap_or(false)
+            {
+                config_files.push(path);
+            }
+        }
+    }
+    config_files
+}
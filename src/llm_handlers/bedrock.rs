@@ -1,20 +1,23 @@
-use anyhow::bail;
-use aws_config::{BehaviorVersion, Region};
+use anyhow::{anyhow, bail};
+
+use aws_config::{retry::RetryConfig, BehaviorVersion, Region};
 use aws_sdk_bedrockruntime::{
-    operation::converse::{ ConverseOutput},
-    types::{ContentBlock, ConversationRole, Message},
+    error::{DisplayErrorContext, SdkError},
+    operation::converse::{ConverseError, ConverseOutput},
+    types::{ContentBlock, ConversationRole, InferenceConfiguration, Message},
     Client,
 };
 
 use crate::configuration::BedrockConfig;
 
-use super::traits::LlmHandler;
-
+use super::traits::{Answer, LlmHandler, RetryableError, TokenUsage};
 
 #[derive(Debug)]
 pub struct BedrockConverse {
     client: Client,
     model_id: String,
+    temperature: Option<f32>,
+    max_tokens: Option<i32>,
 }
 
 impl BedrockConverse {
@@ -22,6 +25,9 @@ impl BedrockConverse {
         let sdk_config = aws_config::defaults(BehaviorVersion::latest())
             .region(Region::new(config.region.clone()))
             .profile_name(config.aws_profile.clone())
+            // `ResilientLLM` retries with its own backoff and times out each
+            // attempt, doing so here as well would multiply the attempts.
+            .retry_config(RetryConfig::disabled())
             .load()
             .await;
 
@@ -30,12 +36,38 @@ impl BedrockConverse {
         Ok(BedrockConverse {
             client,
             model_id: config.model_id.clone(),
+            temperature: config.temperature,
+            max_tokens: config.max_tokens,
+        })
+    }
+
+    pub fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    /// The inference parameters sent with every request.
+    pub fn parameters(&self) -> serde_json::Value {
+        serde_json::json!({
+            "temperature": self.temperature,
+            "max_tokens": self.max_tokens,
         })
     }
+
+    fn inference_config(&self) -> Option<InferenceConfiguration> {
+        if self.temperature.is_none() && self.max_tokens.is_none() {
+            return None;
+        }
+        Some(
+            InferenceConfiguration::builder()
+                .set_temperature(self.temperature)
+                .set_max_tokens(self.max_tokens)
+                .build(),
+        )
+    }
 }
 
 impl LlmHandler for BedrockConverse {
-    async fn answer(&self, prompt: &str) -> anyhow::Result<String> {
+    async fn answer(&self, prompt: &str) -> anyhow::Result<Answer> {
         let response = self
             .client
             .converse()
@@ -48,15 +80,52 @@ impl LlmHandler for BedrockConverse {
                     .content(ContentBlock::Text(prompt.to_string()))
                     .build()?, // .map_err(|_| "failed to build message")?,
             )
+            .set_inference_config(self.inference_config())
             .send()
             .await;
-        let e = get_converse_output_text(response?);
+        let output = response.map_err(|e| converse_error(&self.model_id, e))?;
+        let usage = output.usage().map(|usage| TokenUsage {
+            input_tokens: usage.input_tokens().max(0) as u32,
+            output_tokens: usage.output_tokens().max(0) as u32,
+        });
+        let e = get_converse_output_text(output);
         match e {
-            Ok(s) => Ok(s),
-            Err(_) => bail!("failed to get response"),
+            Ok(text) => Ok(Answer {
+                text,
+                usage,
+                model: self.model_id.clone(),
+            }),
+            Err(e) => bail!("{}: failed to get response: {e}", self.model_id),
         }
     }
 }
+/// Describes a failed request, marking the failures worth retrying.
+fn converse_error<R: std::fmt::Debug>(
+    model_id: &str,
+    error: SdkError<ConverseError, R>,
+) -> anyhow::Error {
+    let retryable = match &error {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(e) => matches!(
+            e.err(),
+            ConverseError::ThrottlingException(_)
+                | ConverseError::ModelTimeoutException(_)
+                | ConverseError::ModelNotReadyException(_)
+                | ConverseError::ServiceUnavailableException(_)
+                | ConverseError::InternalServerException(_)
+        ),
+        _ => false,
+    };
+    let message = format!("{model_id}: {}", DisplayErrorContext(&error));
+    if retryable {
+        RetryableError(message).into()
+    } else {
+        anyhow!(message)
+    }
+}
+
 fn get_converse_output_text(output: ConverseOutput) -> Result<String, String> {
     let text = output
         .output()
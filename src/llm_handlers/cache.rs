@@ -0,0 +1,168 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::replay::prompt_hash;
+use super::traits::{Answer, Llm, LlmHandler};
+use crate::configuration::CacheConfig;
+
+/// Everything that decides the answer to a prompt.
+#[derive(Serialize)]
+struct CacheKey<'a> {
+    backend: &'a str,
+    model: &'a str,
+    parameters: &'a serde_json::Value,
+    prompt: &'a str,
+}
+
+/// A cached answer, stored as `<key>.json` in the cache directory.
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Seconds since the Unix epoch.
+    created: u64,
+    model: String,
+    answer: String,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Wraps another backend and answers repeated prompts from files in a cache
+/// directory.
+pub struct CachedLLM {
+    inner: Arc<Llm>,
+    dir: PathBuf,
+    ttl: Duration,
+    max_size: u64,
+    lock: Mutex<()>,
+}
+
+impl CachedLLM {
+    pub fn new(inner: Arc<Llm>, dir: &Path, config: &CacheConfig) -> Self {
+        CachedLLM {
+            inner,
+            dir: dir.to_path_buf(),
+            ttl: Duration::from_secs(config.ttl_hours * 60 * 60),
+            max_size: config.max_size_mb * 1024 * 1024,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// The same cache in front of another backend.
+    pub fn with_inner(&self, inner: Arc<Llm>) -> Self {
+        CachedLLM {
+            inner,
+            dir: self.dir.clone(),
+            ttl: self.ttl,
+            max_size: self.max_size,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// The backend answering the prompts that are not cached.
+    pub fn inner(&self) -> &Llm {
+        &self.inner
+    }
+
+    /// The file holding the answer of `model` to `prompt`.
+    fn entry_path(&self, model: &str, prompt: &str) -> PathBuf {
+        let parameters = self.inner.parameters();
+        let key = CacheKey {
+            backend: self.inner.backend(),
+            model,
+            parameters: &parameters,
+            prompt,
+        };
+        let key = serde_json::to_string(&key).expect("can serialize cache key");
+        self.dir.join(format!("{}.json", prompt_hash(&key)))
+    }
+
+    fn lookup(&self, path: &Path) -> Option<CacheEntry> {
+        let content = fs::read_to_string(path).ok()?;
+        let entry: CacheEntry = serde_json::from_str(&content).ok()?;
+        if now_secs().saturating_sub(entry.created) > self.ttl.as_secs() {
+            let _ = fs::remove_file(path);
+            return None;
+        }
+        Some(entry)
+    }
+
+    fn store(&self, path: &Path, answer: &Answer) -> anyhow::Result<()> {
+        let entry = CacheEntry {
+            created: now_secs(),
+            model: answer.model.clone(),
+            answer: answer.text.clone(),
+        };
+        let _guard = self.lock.lock().unwrap();
+        fs::create_dir_all(&self.dir)?;
+        fs::write(path, serde_json::to_string(&entry)?)?;
+        evict(&self.dir, self.max_size)?;
+        Ok(())
+    }
+}
+
+impl LlmHandler for CachedLLM {
+    async fn answer(&self, prompt: &str) -> anyhow::Result<Answer> {
+        let path = self.entry_path(&self.inner.model_id(), prompt);
+        if let Some(entry) = self.lookup(&path) {
+            log::debug!("answering from cache {}", path.display());
+            // No tokens were spent on a cached answer.
+            return Ok(Answer::new(entry.answer, &entry.model));
+        }
+        let answer = self.inner.answer_boxed(prompt).await?;
+        // An answer of a fallback is only reused once that model is asked
+        // first.
+        let path = self.entry_path(&answer.model, prompt);
+        if let Err(e) = self.store(&path, &answer) {
+            log::warn!("cannot cache answer in {}: {e}", path.display());
+        }
+        Ok(answer)
+    }
+}
+
+/// The cache files in `dir` with their size and modification time.
+fn entries(dir: &Path) -> anyhow::Result<Vec<(PathBuf, u64, SystemTime)>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let metadata = fs::metadata(&path)?;
+            entries.push((path, metadata.len(), metadata.modified()?));
+        }
+    }
+    Ok(entries)
+}
+
+/// Removes the oldest answers until the cache is at most `max_size` bytes.
+fn evict(dir: &Path, max_size: u64) -> anyhow::Result<()> {
+    let mut entries = entries(dir)?;
+    let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+    entries.sort_by_key(|(_, _, modified)| *modified);
+    for (path, len, _) in entries {
+        if size <= max_size {
+            break;
+        }
+        fs::remove_file(&path)?;
+        size -= len;
+    }
+    Ok(())
+}
+
+/// Removes all cached answers, returns how many there were.
+pub fn clear(dir: &Path) -> anyhow::Result<usize> {
+    let entries = entries(dir)?;
+    for (path, _, _) in entries.iter() {
+        fs::remove_file(path)?;
+    }
+    Ok(entries.len())
+}
@@ -1,18 +1,153 @@
-use super::traits::LlmHandler;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
 
+use anyhow::{anyhow, bail};
+use regex::Regex;
+use serde::Deserialize;
+
+use super::traits::{Answer, LlmHandler, RetryableError};
+
+/// Answers prompts with canned answers, either always the same one or picked
+/// by the first matching rule of a rules file.
 #[derive(Debug)]
 pub struct MockLLM {
-    pub answer: String,
+    rules: Vec<MockRule>,
+    default: Option<String>,
+}
+
+/// A rules file for [`MockLLM`].
+///
+/// ```yaml
+/// default: MOCK
+/// rules:
+///   - regex: 'def (\w+)\('
+///     answer: '"""Do what ${1} does."""'
+///     delay_ms: 200
+///   - contains: "<task>"
+///     error: "ThrottlingException: rate exceeded"
+///     retryable: true
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockRulesConfig {
+    /// Answer for prompts matching no rule, if unset those prompts fail.
+    pub default: Option<String>,
+    #[serde(default)]
+    pub rules: Vec<MockRuleConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockRuleConfig {
+    /// Regex searched in the prompt.
+    pub regex: Option<String>,
+    /// Substring searched in the prompt. A rule without `regex` or `contains`
+    /// matches every prompt.
+    pub contains: Option<String>,
+    /// Answer, where `$1` or `${name}` is replaced by the captured group and
+    /// `$0` by the whole match.
+    pub answer: Option<String>,
+    /// Fail with this error message instead of answering.
+    pub error: Option<String>,
+    /// Whether the error is worth retrying, like throttling.
+    #[serde(default)]
+    pub retryable: bool,
+    /// Wait this long before answering or failing.
+    pub delay_ms: Option<u64>,
+    /// Cut the answer after this many characters.
+    pub truncate: Option<usize>,
+}
+
+#[derive(Debug)]
+struct MockRule {
+    pattern: Regex,
+    answer: String,
+    error: Option<String>,
+    retryable: bool,
+    delay: Option<Duration>,
+    truncate: Option<usize>,
+}
+
+impl MockRule {
+    fn try_from_config(config: MockRuleConfig) -> anyhow::Result<Self> {
+        let pattern = match (config.regex, config.contains) {
+            (Some(_), Some(_)) => bail!("a rule can use either `regex` or `contains`, not both"),
+            (Some(regex), None) => Regex::new(&regex)?,
+            (None, Some(contains)) => Regex::new(&regex::escape(&contains))?,
+            (None, None) => Regex::new("")?,
+        };
+        if config.answer.is_none() && config.error.is_none() {
+            bail!("a rule needs an `answer` or an `error`");
+        }
+        Ok(MockRule {
+            pattern,
+            answer: config.answer.unwrap_or_default(),
+            error: config.error,
+            retryable: config.retryable,
+            delay: config.delay_ms.map(Duration::from_millis),
+            truncate: config.truncate,
+        })
+    }
 }
 
 impl MockLLM {
-    pub fn new(answer: String) -> anyhow::Result<Self> {
-        Ok(MockLLM { answer })
+    pub fn new(answer: String) -> Self {
+        MockLLM {
+            rules: Vec::new(),
+            default: Some(answer),
+        }
+    }
+
+    pub fn from_rules_file(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow!("cannot read mock rules {}: {e}", path.display()))?;
+        let config: MockRulesConfig =
+            serde_yaml::from_str(&content).map_err(|e| anyhow!("{}: {e}", path.display()))?;
+        let rules = config
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(idx, rule)| {
+                MockRule::try_from_config(rule)
+                    .map_err(|e| anyhow!("{}: rule {}: {e}", path.display(), idx + 1))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(MockLLM {
+            rules,
+            default: config.default,
+        })
     }
 }
 
 impl LlmHandler for MockLLM {
-    async fn answer(&self, _: &str) -> anyhow::Result<String> {
-        Ok(self.answer.clone())
+    async fn answer(&self, prompt: &str) -> anyhow::Result<Answer> {
+        let Some((rule, captures)) = self
+            .rules
+            .iter()
+            .find_map(|rule| rule.pattern.captures(prompt).map(|c| (rule, c)))
+        else {
+            return self
+                .default
+                .clone()
+                .map(|text| Answer::new(text, "mock"))
+                .ok_or_else(|| anyhow!("no mock rule matches the prompt"));
+        };
+
+        if let Some(delay) = rule.delay {
+            tokio::time::sleep(delay).await;
+        }
+        if let Some(error) = &rule.error {
+            if rule.retryable {
+                return Err(RetryableError(error.clone()).into());
+            }
+            bail!("{error}");
+        }
+        let mut answer = String::new();
+        captures.expand(&rule.answer, &mut answer);
+        if let Some(truncate) = rule.truncate {
+            answer = answer.chars().take(truncate).collect();
+        }
+        Ok(Answer::new(answer, "mock"))
     }
 }
@@ -1,3 +1,7 @@
 pub mod bedrock;
+pub mod cache;
 pub mod mock;
+pub mod replay;
+pub mod resilient;
+pub mod tracked;
 pub mod traits;
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::traits::{Answer, Llm, LlmHandler, TokenUsage};
+
+/// A single prompt and its answer, stored as one line of a JSONL fixture file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Recording {
+    pub prompt_hash: String,
+    pub prompt: String,
+    pub answer: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+}
+
+/// Returns the key under which the answer to `prompt` is stored.
+pub fn prompt_hash(prompt: &str) -> String {
+    Sha256::digest(prompt.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Wraps another backend and appends every prompt and answer to a fixture file.
+pub struct RecordLLM {
+    inner: Arc<Llm>,
+    fixture: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl RecordLLM {
+    pub fn new(inner: Arc<Llm>, fixture: &Path) -> Self {
+        RecordLLM {
+            inner,
+            fixture: fixture.to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn model_id(&self) -> String {
+        self.inner.model_id()
+    }
+
+    /// The same fixture recording another backend.
+    pub fn with_inner(&self, inner: Arc<Llm>) -> Self {
+        RecordLLM {
+            inner,
+            fixture: self.fixture.clone(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// The backend whose answers are recorded.
+    pub fn inner(&self) -> &Llm {
+        &self.inner
+    }
+
+    fn record(&self, prompt: &str, answer: &Answer) -> anyhow::Result<()> {
+        let recording = Recording {
+            prompt_hash: prompt_hash(prompt),
+            prompt: prompt.to_owned(),
+            answer: answer.text.clone(),
+            usage: answer.usage,
+        };
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.fixture)?;
+        writeln!(file, "{}", serde_json::to_string(&recording)?)?;
+        Ok(())
+    }
+}
+
+impl LlmHandler for RecordLLM {
+    async fn answer(&self, prompt: &str) -> anyhow::Result<Answer> {
+        let answer = self.inner.answer_boxed(prompt).await?;
+        self.record(prompt, &answer)?;
+        Ok(answer)
+    }
+}
+
+/// Answers prompts from a fixture file written by [`RecordLLM`].
+#[derive(Debug)]
+pub struct ReplayLLM {
+    fixture: PathBuf,
+    answers: HashMap<String, Answer>,
+}
+
+impl ReplayLLM {
+    pub fn from_file(fixture: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(fixture)
+            .map_err(|e| anyhow!("cannot read fixture {}: {e}", fixture.display()))?;
+        let mut answers = HashMap::new();
+        for (idx, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let recording: Recording = serde_json::from_str(line)
+                .map_err(|e| anyhow!("{}:{}: {e}", fixture.display(), idx + 1))?;
+            answers.insert(
+                recording.prompt_hash,
+                Answer {
+                    text: recording.answer,
+                    usage: recording.usage,
+                    model: "replay".to_owned(),
+                },
+            );
+        }
+        Ok(ReplayLLM {
+            fixture: fixture.to_path_buf(),
+            answers,
+        })
+    }
+}
+
+impl LlmHandler for ReplayLLM {
+    async fn answer(&self, prompt: &str) -> anyhow::Result<Answer> {
+        let hash = prompt_hash(prompt);
+        match self.answers.get(&hash) {
+            Some(answer) => Ok(answer.clone()),
+            None => bail!(
+                "no recorded answer for prompt {hash} in {}, re-record it with --record",
+                self.fixture.display()
+            ),
+        }
+    }
+}
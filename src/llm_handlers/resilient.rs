@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+use super::bedrock::BedrockConverse;
+use super::traits::{Answer, Llm, LlmHandler, RetryableError};
+use crate::configuration::{ModelConfig, RetryConfig};
+
+/// Asks a chain of backends in order. Every attempt is limited in time, and
+/// attempts failing with a retryable error are repeated with exponential
+/// backoff before the next backend of the chain is asked.
+pub struct ResilientLLM {
+    chain: Vec<Arc<Llm>>,
+    retry: RetryConfig,
+}
+
+impl ResilientLLM {
+    pub fn new(chain: Vec<Arc<Llm>>, retry: RetryConfig) -> Self {
+        assert!(!chain.is_empty(), "the fallback chain needs a backend");
+        ResilientLLM { chain, retry }
+    }
+
+    /// Creates the configured Bedrock model followed by its fallbacks.
+    pub async fn from_config(config: &ModelConfig) -> anyhow::Result<Self> {
+        let mut chain = Vec::new();
+        for bedrock in std::iter::once(&config.bedrock).chain(config.fallbacks.iter()) {
+            chain.push(Arc::new(Llm::Bedrock(BedrockConverse::new(bedrock).await?)));
+        }
+        Ok(Self::new(chain, config.retry.clone()))
+    }
+
+    /// The backend asked first.
+    pub fn primary(&self) -> &Llm {
+        &self.chain[0]
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .retry
+            .initial_backoff_ms
+            .saturating_mul(2u64.saturating_pow(retry));
+        Duration::from_millis(backoff.min(self.retry.max_backoff_ms))
+    }
+
+    /// Asks `llm`, retrying timeouts and retryable errors.
+    async fn answer_with_retries(&self, llm: &Llm, prompt: &str) -> anyhow::Result<Answer> {
+        let timeout = Duration::from_secs(self.retry.attempt_timeout_secs);
+        let mut retry = 0;
+        loop {
+            let error = match tokio::time::timeout(timeout, llm.answer_boxed(prompt)).await {
+                Ok(Ok(answer)) => return Ok(answer),
+                Ok(Err(e)) if e.downcast_ref::<RetryableError>().is_none() => return Err(e),
+                Ok(Err(e)) => e,
+                Err(_) => anyhow!(
+                    "{}: no answer within {}s",
+                    llm.model_id(),
+                    timeout.as_secs()
+                ),
+            };
+            if retry >= self.retry.max_retries {
+                return Err(error);
+            }
+            let backoff = self.backoff(retry);
+            log::warn!("{error}, retrying in {}ms", backoff.as_millis());
+            tokio::time::sleep(backoff).await;
+            retry += 1;
+        }
+    }
+}
+
+impl LlmHandler for ResilientLLM {
+    async fn answer(&self, prompt: &str) -> anyhow::Result<Answer> {
+        let mut errors = Vec::new();
+        for llm in self.chain.iter() {
+            match self.answer_with_retries(llm, prompt).await {
+                Ok(answer) => return Ok(answer),
+                Err(e) => {
+                    log::warn!("{} failed: {e}", llm.model_id());
+                    errors.push(e);
+                }
+            }
+        }
+        if errors.len() == 1 {
+            return Err(errors.remove(0));
+        }
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        Err(anyhow!("all models failed: {}", errors.join("; ")))
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use super::traits::{Answer, Llm, LlmHandler};
+use crate::usage::UsageTracker;
+
+/// Wraps a backend whose calls count against the token usage. The calls are
+/// recorded per action by `candidates::complete`, which knows the action.
+pub struct TrackedLLM {
+    inner: Arc<Llm>,
+    tracker: Arc<UsageTracker>,
+}
+
+impl TrackedLLM {
+    pub fn new(inner: Arc<Llm>, tracker: Arc<UsageTracker>) -> Self {
+        TrackedLLM { inner, tracker }
+    }
+
+    /// The same tracker for another backend.
+    pub fn with_inner(&self, inner: Arc<Llm>) -> Self {
+        TrackedLLM {
+            inner,
+            tracker: self.tracker.clone(),
+        }
+    }
+
+    /// The backend whose usage is tracked.
+    pub fn inner(&self) -> &Llm {
+        &self.inner
+    }
+
+    pub fn tracker(&self) -> &Arc<UsageTracker> {
+        &self.tracker
+    }
+}
+
+impl LlmHandler for TrackedLLM {
+    async fn answer(&self, prompt: &str) -> anyhow::Result<Answer> {
+        self.inner.answer_boxed(prompt).await
+    }
+}
@@ -1,23 +1,166 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
 use super::bedrock::BedrockConverse;
+use super::cache::CachedLLM;
 use super::mock::MockLLM;
+use super::replay::{RecordLLM, ReplayLLM};
+use super::resilient::ResilientLLM;
+use super::tracked::TrackedLLM;
+use crate::usage::UsageTracker;
+
+/// Tokens consumed by a single request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+/// The answer of a model and, if the backend reports it, its token usage.
+#[derive(Debug, Clone)]
+pub struct Answer {
+    pub text: String,
+    pub usage: Option<TokenUsage>,
+    /// The model that answered, which may be a fallback of `Llm::model_id`.
+    pub model: String,
+}
+
+impl Answer {
+    /// The answer `text` of `model`, without token usage.
+    pub fn new(text: String, model: &str) -> Self {
+        Answer {
+            text,
+            usage: None,
+            model: model.to_owned(),
+        }
+    }
+}
+
+/// A failure that may go away when the request is repeated, e.g. throttling.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct RetryableError(pub String);
 
 pub trait LlmHandler {
     fn answer(
         &self,
         prompt: &str,
-    ) -> impl std::future::Future<Output = anyhow::Result<String>> + Send;
+    ) -> impl std::future::Future<Output = anyhow::Result<Answer>> + Send;
 }
 
 pub enum Llm {
     Bedrock(BedrockConverse),
     Mock(MockLLM),
+    Record(RecordLLM),
+    Replay(ReplayLLM),
+    Cached(CachedLLM),
+    Resilient(ResilientLLM),
+    Tracked(TrackedLLM),
 }
 
 impl Llm {
     pub async fn answer<'a>(&'a self, prompt: &'a str) -> anyhow::Result<String> {
+        Ok(self.answer_with_usage(prompt).await?.text)
+    }
+
+    /// `answer_with_usage` boxed, for the wrappers around another backend. It
+    /// breaks the cycle between `Llm::answer_with_usage` and the `answer` of
+    /// the wrapper when checking that both are `Send`.
+    pub fn answer_boxed<'a>(
+        &'a self,
+        prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Answer>> + Send + 'a>> {
+        Box::pin(self.answer_with_usage(prompt))
+    }
+
+    pub async fn answer_with_usage<'a>(&'a self, prompt: &'a str) -> anyhow::Result<Answer> {
         match self {
             Llm::Bedrock(b) => b.answer(prompt).await,
             Llm::Mock(b) => b.answer(prompt).await,
+            Llm::Record(b) => b.answer(prompt).await,
+            Llm::Replay(b) => b.answer(prompt).await,
+            Llm::Cached(b) => b.answer(prompt).await,
+            Llm::Resilient(b) => b.answer(prompt).await,
+            Llm::Tracked(b) => b.answer(prompt).await,
+        }
+    }
+
+    /// The name of the model asked first. The answers name the model that
+    /// actually answered.
+    pub fn model_id(&self) -> String {
+        match self {
+            Llm::Bedrock(b) => b.model_id().to_owned(),
+            Llm::Mock(_) => "mock".to_owned(),
+            Llm::Record(b) => b.model_id(),
+            Llm::Replay(_) => "replay".to_owned(),
+            Llm::Cached(b) => b.inner().model_id(),
+            Llm::Resilient(b) => b.primary().model_id(),
+            Llm::Tracked(b) => b.inner().model_id(),
+        }
+    }
+
+    /// The name of the backend answering the prompts.
+    pub fn backend(&self) -> &'static str {
+        match self {
+            Llm::Bedrock(_) => "bedrock",
+            Llm::Mock(_) => "mock",
+            Llm::Record(b) => b.inner().backend(),
+            Llm::Replay(_) => "replay",
+            Llm::Cached(b) => b.inner().backend(),
+            Llm::Resilient(b) => b.primary().backend(),
+            Llm::Tracked(b) => b.inner().backend(),
+        }
+    }
+
+    /// The settings besides the model that change the answer to a prompt.
+    pub fn parameters(&self) -> serde_json::Value {
+        match self {
+            Llm::Bedrock(b) => b.parameters(),
+            Llm::Mock(_) | Llm::Replay(_) => serde_json::Value::Null,
+            Llm::Record(b) => b.inner().parameters(),
+            Llm::Cached(b) => b.inner().parameters(),
+            Llm::Resilient(b) => b.primary().parameters(),
+            Llm::Tracked(b) => b.inner().parameters(),
+        }
+    }
+
+    /// Whether the prompts go to Bedrock, whose model can be switched.
+    pub fn is_bedrock(&self) -> bool {
+        self.backend() == "bedrock"
+    }
+
+    /// The same cache and recording in front of another `backend`, for when
+    /// the model config changed.
+    pub fn with_backend(&self, backend: Arc<Llm>) -> Arc<Llm> {
+        match self {
+            Llm::Cached(b) => Arc::new(Llm::Cached(b.with_inner(b.inner().with_backend(backend)))),
+            Llm::Record(b) => Arc::new(Llm::Record(b.with_inner(b.inner().with_backend(backend)))),
+            Llm::Tracked(b) => {
+                Arc::new(Llm::Tracked(b.with_inner(b.inner().with_backend(backend))))
+            }
+            _ => backend,
+        }
+    }
+
+    /// The tracker the calls to this backend count against, if usage is
+    /// tracked.
+    pub fn usage(&self) -> Option<&Arc<UsageTracker>> {
+        match self {
+            Llm::Tracked(b) => Some(b.tracker()),
+            Llm::Cached(b) => b.inner().usage(),
+            Llm::Record(b) => b.inner().usage(),
+            _ => None,
+        }
+    }
+
+    /// Skips the response cache, for actions whose answers should never repeat.
+    pub fn uncached(&self) -> &Llm {
+        match self {
+            Llm::Cached(b) => b.inner(),
+            llm => llm,
         }
     }
 }
@@ -0,0 +1,128 @@
+//! File logging through log4rs and the audit log of resolved code actions.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use log::LevelFilter;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::config::{Appender, Config, Logger, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use serde::Serialize;
+use tower_lsp::lsp_types::Url;
+
+use crate::configuration::LoggingConfig;
+use crate::llm_handlers::traits::{Answer, TokenUsage};
+
+/// The log target audit records are written to.
+pub const AUDIT_TARGET: &str = "audit";
+
+fn rolling_appender(
+    path: &Path,
+    pattern: &str,
+    config: &LoggingConfig,
+) -> anyhow::Result<RollingFileAppender> {
+    let roller = FixedWindowRoller::builder()
+        .build(&format!("{}.{{}}", path.display()), config.max_files)
+        .map_err(|e| anyhow!("{e}"))?;
+    let policy = CompoundPolicy::new(
+        Box::new(SizeTrigger::new(config.max_size_mb * 1024 * 1024)),
+        Box::new(roller),
+    );
+    Ok(RollingFileAppender::builder()
+        .encoder(Box::new(PatternEncoder::new(pattern)))
+        .build(path, Box::new(policy))?)
+}
+
+/// Sends the `log` records of the whole process to rotating files below
+/// `log_dir`, unless the config points somewhere else.
+pub fn init(config: &LoggingConfig, log_dir: &Path) -> anyhow::Result<()> {
+    let level: LevelFilter = config
+        .level
+        .parse()
+        .map_err(|_| anyhow!("invalid log level `{}`", config.level))?;
+    let log_file = config
+        .file
+        .clone()
+        .unwrap_or_else(|| log_dir.join("polyglot_ls.log"));
+    let log_appender = rolling_appender(
+        &log_file,
+        "{d(%Y-%m-%d %H:%M:%S%.3f)} {l:<5} {t} - {m}{n}",
+        config,
+    )?;
+
+    let mut builder =
+        Config::builder().appender(Appender::builder().build("file", Box::new(log_appender)));
+    if config.audit {
+        let audit_file: PathBuf = config
+            .audit_file
+            .clone()
+            .unwrap_or_else(|| log_dir.join("audit.jsonl"));
+        let audit_appender = rolling_appender(&audit_file, "{m}{n}", config)?;
+        builder = builder
+            .appender(Appender::builder().build("audit", Box::new(audit_appender)))
+            .logger(
+                Logger::builder()
+                    .appender("audit")
+                    .additive(false)
+                    .build(AUDIT_TARGET, LevelFilter::Info),
+            );
+    } else {
+        builder = builder.logger(Logger::builder().build(AUDIT_TARGET, LevelFilter::Off));
+    }
+    let log_config = builder.build(Root::builder().appender("file").build(level))?;
+    log4rs::init_config(log_config)?;
+    Ok(())
+}
+
+/// One resolved code action, written as a JSON line to the audit log.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord<'a> {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub action_id: &'a str,
+    pub file: &'a str,
+    pub model: &'a str,
+    pub prompt: &'a str,
+    pub raw_answer: &'a str,
+    /// The text inserted into the document, `None` if the answer was discarded.
+    pub processed_answer: Option<&'a str>,
+    pub latency_ms: u64,
+    pub usage: Option<TokenUsage>,
+}
+
+/// Writes an audit record for the action `action_id` resolved in `uri`, if the
+/// audit log is enabled.
+pub fn audit(
+    action_id: &str,
+    uri: &Url,
+    prompt: &str,
+    answer: &Answer,
+    processed_answer: Option<&str>,
+    latency: Duration,
+) {
+    if !log::log_enabled!(target: AUDIT_TARGET, log::Level::Info) {
+        return;
+    }
+    let record = AuditRecord {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        action_id,
+        file: uri.as_str(),
+        model: &answer.model,
+        prompt,
+        raw_answer: &answer.text,
+        processed_answer,
+        latency_ms: latency.as_millis() as u64,
+        usage: answer.usage,
+    };
+    match serde_json::to_string(&record) {
+        Ok(line) => log::info!(target: AUDIT_TARGET, "{line}"),
+        Err(e) => log::warn!("cannot serialize audit record: {e}"),
+    }
+}
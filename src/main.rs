@@ -1,48 +1,23 @@
-use clap::{ArgGroup, Parser};
-use code_action_providers::load_providers;
-use code_action_providers::parsed_document::ParsedDocument;
-use llm_handlers::bedrock::BedrockConverse;
-use llm_handlers::mock::MockLLM;
-use llm_handlers::traits::Llm;
-use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use clap::{ArgGroup, Parser, Subcommand};
+use polyglot_ls::code_action_providers::{
+    disable_actions, load_providers, merge_providers, LoadError, ProviderMap,
+};
+use polyglot_ls::llm_handlers::cache::{self, CachedLLM};
+use polyglot_ls::llm_handlers::mock::MockLLM;
+use polyglot_ls::llm_handlers::replay::{RecordLLM, ReplayLLM};
+use polyglot_ls::llm_handlers::resilient::ResilientLLM;
+use polyglot_ls::llm_handlers::tracked::TrackedLLM;
+use polyglot_ls::llm_handlers::traits::Llm;
+use polyglot_ls::usage::UsageTracker;
+use polyglot_ls::{apply, chat, check, configuration, logging, server, usage};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::{env, io};
 
-pub mod code_action_providers;
-pub mod configuration;
-pub mod llm_handlers;
-pub mod nonsense;
-pub mod server;
-
 use tokio::net::{TcpListener, TcpStream};
-use tower_lsp::lsp_types::*;
 use tower_lsp::{LspService, Server};
 
-/// Reads all language configuration files in the specified directory that
-/// match the given filter.
-///
-/// # Arguments
-///
-/// * `config_dir` - The directory containing the configuration files.
-/// * `filter` - The file extension filter to apply.
-///
-/// # Returns
-///
-/// A vector of `PathBuf` containing the paths of the matching configuration files.
-fn read_language_config_files(config_dir: &Path, filter: &str) -> Vec<PathBuf> {
-    let mut config_files = Vec::new();
-    if let Ok(entries) = std::fs::read_dir(config_dir) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if (path.is_file() || path.is_symlink())
-                && path.extension().map(|ext| ext == filter).unwrap_or(false)
-            {
-                config_files.push(path);
-            }
-        }
-    }
-    config_files
-}
 // https://github.com/microsoft/vscode-languageserver-node/blob/0cb3812e7d540ef3a904e96df795bc37a21de9b0/client/src/node/main.ts#L378-L387
 #[derive(Parser)]
 #[command(
@@ -53,8 +28,9 @@ fn read_language_config_files(config_dir: &Path, filter: &str) -> Vec<PathBuf> {
 #[command(group(
     ArgGroup::new("input")
         .required(true)
-        .args(&["socket", "stdio", "bind", "answer"]),
+        .args(&["socket", "stdio", "bind", "answer", "print_config"]),
 ))]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
     /// Socket the LSP server will listen on
     #[arg(long)]
@@ -68,18 +44,109 @@ struct Args {
     #[arg(long)]
     stdio: bool, // Just a flag no value needed
 
-    /// LSP server will read prompt from stdin and reply in stdout
+    /// Read a prompt from stdin until EOF and print the answer to stdout
     #[arg(long)]
     answer: bool, // Just a flag no value needed
 
-    /// Will consume text from stdin, code_action id and cursor position from the arg
-    /// and return the alternated text to stdout.
-    #[arg(long)]
-    use_mock: bool,
+    /// Answer every prompt with "MOCK" instead of calling the model, or with
+    /// the canned answers of a mock rules file
+    #[arg(long, value_name = "RULES", num_args = 0..=1, require_equals = true)]
+    use_mock: Option<Option<PathBuf>>,
+
+    /// Answer prompts from a JSONL fixture written with --record instead of calling the model
+    #[arg(long, conflicts_with = "use_mock")]
+    replay: Option<PathBuf>,
+
+    /// Append every prompt and answer to this JSONL fixture file
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
 
     /// Path to polyglot configuration YAML file
     #[arg(long)]
-    polyglot_config_path: Option<String>,
+    polyglot_config_path: Option<PathBuf>,
+
+    /// Directory holding the server config, code actions and logs
+    #[arg(long)]
+    config_dir: Option<PathBuf>,
+
+    /// Print the resolved configuration layout and exit
+    #[arg(long)]
+    print_config: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Validate the server config and all code actions, then exit
+    Check {
+        /// Print the JSON Schema of the YAML code action format instead
+        #[arg(long)]
+        schema: bool,
+    },
+    /// Run a code action on a file and print the edited file to stdout
+    Apply(apply::ApplyArgs),
+    /// Chat with the configured model
+    Chat(chat::ChatArgs),
+    /// Print the prompt a code action would send, without calling the model
+    Prompt(apply::TargetArgs),
+    /// Manage the cache of model answers
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Show the tokens spent per day, model and action
+    Stats {
+        /// Number of days to show, including today
+        #[arg(long, default_value_t = 7)]
+        days: u32,
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Remove all cached answers
+    Clear,
+}
+
+/// Loads the user-level code actions and, if trusted, the ones of the project
+/// in the current directory. Load errors are printed to stderr.
+fn load_cli_providers(
+    layout: &configuration::ConfigLayout,
+    polyglot_config: &configuration::PolyglotConfig,
+    prompt_handler: Arc<Llm>,
+) -> ProviderMap {
+    let (mut providers, mut errors) =
+        load_providers(layout.code_actions_dir.clone(), prompt_handler.clone());
+
+    let project_dir = PathBuf::from(configuration::ProjectConfig::DIR_NAME);
+    let cwd = env::current_dir().unwrap_or_default();
+    if project_dir.is_dir() && polyglot_config.is_trusted_project(&cwd) {
+        let (project_providers, project_errors) =
+            load_providers(project_dir.join("code_actions"), prompt_handler);
+        merge_providers(&mut providers, project_providers);
+        errors.extend(project_errors);
+        let project_config_path = project_dir.join("config.yaml");
+        if project_config_path.exists() {
+            match configuration::ProjectConfig::try_read_from_file(&project_config_path) {
+                Ok(project_config) => {
+                    disable_actions(&mut providers, &project_config.disabled_actions)
+                }
+                Err(e) => errors.push(LoadError {
+                    path: project_config_path,
+                    message: e.to_string(),
+                }),
+            }
+        }
+    }
+    disable_actions(&mut providers, &polyglot_config.disabled_actions);
+
+    for error in errors {
+        eprintln!("Cannot load {error}");
+    }
+    providers
 }
 
 #[tokio::main]
@@ -88,59 +155,199 @@ async fn main() {
     #[cfg(feature = "runtime-agnostic")]
     use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 
-    let home_dir = env::var("HOME").expect("Failed to get home directory");
-    let config_base_dir = Path::new(&home_dir).join(".config").join("polyglot_ls");
-
-    let polyglot_config_path = args.polyglot_config_path.unwrap_or(
-        config_base_dir
-            .join("server_config.yaml")
-            .to_string_lossy()
-            .to_string(),
+    let layout = configuration::ConfigLayout::resolve(
+        args.config_dir.as_deref(),
+        args.polyglot_config_path.as_deref(),
     );
-    let polyglot_config = configuration::PolyglotConfig::try_read_from_file(&polyglot_config_path)
-        .unwrap_or_default();
 
-    if args.stdio || args.answer {
+    if let Some(Command::Check { schema }) = args.command {
+        if schema {
+            println!("{}", check::code_action_schema());
+            return;
+        }
+        let is_valid = check::check_configs(&layout);
+        std::process::exit(if is_valid { 0 } else { 1 });
+    }
+
+    // A broken server config is an error, not a reason to run on defaults.
+    let polyglot_config = if layout.server_config.exists() {
+        match configuration::PolyglotConfig::try_read_from_file(&layout.server_config) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Cannot read {}: {e}", layout.server_config.display());
+                std::process::exit(1);
+            }
+        }
     } else {
-        tracing_subscriber::fmt::init();
+        configuration::PolyglotConfig::default()
+    };
+
+    if args.print_config {
+        print!("{layout}");
+        println!();
+        print!(
+            "{}",
+            serde_yaml::to_string(&polyglot_config).expect("can serialize config")
+        );
+        return;
     }
-    //log::info!("Start");
-    let prompt_handler;
 
-    if args.use_mock {
-        prompt_handler = Arc::new(Llm::Mock(MockLLM {
-            answer: "MOCK".to_string(),
-        }));
+    if let Some(Command::Cache(CacheCommand::Clear)) = args.command {
+        match cache::clear(&layout.cache_dir) {
+            Ok(count) => println!("Removed {count} cached answers"),
+            Err(e) => {
+                eprintln!("Cannot clear {}: {e}", layout.cache_dir.display());
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Stats { days, json }) = args.command {
+        match usage::summarize(&layout.usage_file, &polyglot_config.usage, days) {
+            Ok(summary) if json => println!(
+                "{}",
+                serde_json::to_string_pretty(&summary).expect("can serialize usage")
+            ),
+            Ok(summary) => usage::print_summary(&summary),
+            Err(e) => {
+                eprintln!("Cannot read {}: {e}", layout.usage_file.display());
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Prompt(target_args)) = args.command {
+        // The model is never asked, so there is no need to connect to it.
+        let prompt_handler = Arc::new(Llm::Mock(MockLLM::new("MOCK".to_string())));
+        let providers = load_cli_providers(&layout, &polyglot_config, prompt_handler);
+        if let Err(e) = apply::run_prompt(target_args, &providers) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // One-shot commands report on stderr and leave no log files behind.
+    if args.command.is_none() && !args.answer {
+        if let Err(e) = logging::init(&polyglot_config.logging, &layout.log_dir) {
+            eprintln!("Cannot set up logging: {e}");
+        }
+    }
+    if args.socket.is_some() || args.bind.is_some() {
+        // The subscriber is installed before its `log` bridge, which fails
+        // once log4rs owns the `log` records, so that error is expected.
+        let _ = tracing_subscriber::fmt().try_init();
+    }
+    log::info!("Start");
+    let mut prompt_handler;
+
+    if let Some(rules) = &args.use_mock {
+        let mock = match rules {
+            Some(rules) => MockLLM::from_rules_file(rules),
+            None => Ok(MockLLM::new("MOCK".to_string())),
+        };
+        match mock {
+            // Retries and timeouts apply to the mock as well, so that rules
+            // with `retryable` errors or delays can exercise them.
+            Ok(mock) => {
+                prompt_handler = Arc::new(Llm::Resilient(ResilientLLM::new(
+                    vec![Arc::new(Llm::Mock(mock))],
+                    polyglot_config.model.retry.clone(),
+                )))
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(fixture) = &args.replay {
+        match ReplayLLM::from_file(fixture) {
+            Ok(replay) => prompt_handler = Arc::new(Llm::Replay(replay)),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
     } else {
-        prompt_handler = Arc::new(Llm::Bedrock(
-            BedrockConverse::new(&polyglot_config.model.bedrock)
-                .await
-                .unwrap(),
-        ));
+        match ResilientLLM::from_config(&polyglot_config.model).await {
+            Ok(llm) => prompt_handler = Arc::new(Llm::Resilient(llm)),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(fixture) = &args.record {
+        prompt_handler = Arc::new(Llm::Record(RecordLLM::new(prompt_handler, fixture)));
+    }
+    // Mock and replayed answers cost nothing, so only Bedrock is tracked.
+    let usage = (polyglot_config.usage.enabled && prompt_handler.is_bedrock()).then(|| {
+        Arc::new(UsageTracker::new(
+            layout.usage_file.clone(),
+            polyglot_config.usage.clone(),
+        ))
+    });
+    if let Some(usage) = &usage {
+        prompt_handler = Arc::new(Llm::Tracked(TrackedLLM::new(prompt_handler, usage.clone())));
+    }
+    if polyglot_config.cache.enabled {
+        prompt_handler = Arc::new(Llm::Cached(CachedLLM::new(
+            prompt_handler,
+            &layout.cache_dir,
+            &polyglot_config.cache,
+        )));
     }
     if args.answer {
         let mut prompt = String::new();
         io::stdin()
-            .read_line(&mut prompt)
+            .read_to_string(&mut prompt)
             .expect("Failed to read from stdin");
-        let result = prompt_handler.answer(&prompt).await;
-        if let Ok(answer) = result {
-            println!("{}", &answer);
-        } else {
-            println!("{:?}", &result);
+        let answer = match usage.as_ref().map_or(Ok(()), |usage| usage.check_budget()) {
+            Ok(()) => prompt_handler.answer_with_usage(&prompt).await,
+            Err(e) => Err(e),
+        };
+        match answer {
+            Ok(answer) => {
+                if let Some(usage) = &usage {
+                    usage.record("answer", &answer.model, answer.usage);
+                }
+                println!("{}", &answer.text)
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Apply(apply_args)) = args.command {
+        let providers = load_cli_providers(&layout, &polyglot_config, prompt_handler);
+        if let Err(e) = apply::run(apply_args, &providers).await {
+            eprintln!("{e}");
+            std::process::exit(1);
         }
         return;
     }
 
-    let providers = load_providers(config_base_dir.join("code_actions"), prompt_handler);
+    if let Some(Command::Chat(chat_args)) = args.command {
+        if let Err(e) = chat::run(chat_args, prompt_handler, polyglot_config.model).await {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let (service, socket) = LspService::new(|client| server::Backend {
-        client,
-        current_text: Arc::new("".to_string().into()),
-        current_language: Arc::new("".to_string().into()),
-        providers,
-        parsed_doc: ParsedDocument::new("", &Url::parse("http://example.com").unwrap(), ""),
-        indexed_text: Arc::new(RwLock::new(nonsense::IndexedText::new("".to_owned()))),
+    let (service, socket) = LspService::new(|client| {
+        server::Backend::new(
+            client,
+            layout.code_actions_dir,
+            prompt_handler,
+            polyglot_config,
+            usage,
+        )
     });
 
     if let Some(port) = args.socket {
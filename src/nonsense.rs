@@ -30,7 +30,7 @@
 //! Below is a an example where the original text is `&'static str`.
 //!
 //! ```rust
-//! use lsp_document::{TextMap, TextAdapter, Pos, IndexedText};
+//! use polyglot_ls::nonsense::{TextMap, TextAdapter, Pos, IndexedText};
 //! use tower_lsp::lsp_types::Position;
 //!
 //! // Character width
@@ -0,0 +1,102 @@
+//! Resolved code actions that are shown as a diff and only applied once the
+//! user confirms them.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use tower_lsp::lsp_types::{CodeAction, Url, WorkspaceEdit};
+
+use crate::apply::{apply_edits, created_files, unified_diff};
+
+/// The URI scheme of the virtual documents showing a pending edit.
+pub const SCHEME: &str = "polyglot-preview";
+
+/// An edit waiting for the user to apply or discard it.
+#[derive(Debug, Clone)]
+pub struct PendingEdit {
+    /// The title of the action that produced the edit.
+    pub title: String,
+    /// The document the action was run on.
+    pub document: Url,
+    /// The edit in LSP positions, ready to be sent to the client.
+    pub edit: WorkspaceEdit,
+    /// The unified diff shown to the user.
+    pub diff: String,
+}
+
+/// All pending edits, keyed by the id in their preview URI.
+#[derive(Debug, Default)]
+pub struct Previews {
+    next_id: u64,
+    pending: HashMap<u64, PendingEdit>,
+}
+
+impl Previews {
+    /// Stores `pending` and returns the URI of its preview document.
+    pub fn insert(&mut self, pending: PendingEdit) -> Url {
+        self.next_id += 1;
+        let uri = preview_uri(self.next_id, &pending.document);
+        self.pending.insert(self.next_id, pending);
+        uri
+    }
+
+    /// The pending edit shown by the preview document `uri`.
+    pub fn get(&self, uri: &Url) -> Option<&PendingEdit> {
+        self.pending.get(&preview_id(uri)?)
+    }
+
+    /// Removes the pending edit shown by the preview document `uri`.
+    pub fn remove(&mut self, uri: &Url) -> Option<PendingEdit> {
+        self.pending.remove(&preview_id(uri)?)
+    }
+
+    /// Drops all pending edits of `document`, whose ranges no longer match
+    /// once it changed. Returns how many were dropped.
+    pub fn invalidate(&mut self, document: &Url) -> usize {
+        let before = self.pending.len();
+        self.pending
+            .retain(|_, pending| &pending.document != document);
+        before - self.pending.len()
+    }
+}
+
+/// The URI of the preview document `id`, like
+/// `polyglot-preview:/3/main.py.diff`, so editors pick diff highlighting.
+fn preview_uri(id: u64, document: &Url) -> Url {
+    let name = document
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("edit");
+    Url::parse(&format!("{SCHEME}:/{id}/{name}.diff")).expect("valid preview uri")
+}
+
+/// The id of the preview document `uri`, if it is one.
+fn preview_id(uri: &Url) -> Option<u64> {
+    if uri.scheme() != SCHEME {
+        return None;
+    }
+    uri.path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Returns true if `uri` is a preview document.
+pub fn is_preview(uri: &Url) -> bool {
+    uri.scheme() == SCHEME
+}
+
+/// Renders the changes of a resolved `action` on `source`, the text of
+/// `document`, as a unified diff. The edits must still be in UTF-8 columns.
+pub fn render_diff(source: &str, document: &Url, action: &CodeAction) -> String {
+    let path = |uri: &Url| -> PathBuf { uri.to_file_path().unwrap_or_else(|_| uri.path().into()) };
+    let edited = apply_edits(source, document, action);
+    let mut diff = unified_diff(&path(document), source, &edited);
+    for (file, content) in created_files(action) {
+        diff.push_str(&unified_diff(&path(&file), "", &content));
+    }
+    diff
+}
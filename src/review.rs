@@ -0,0 +1,314 @@
+//! Reviews of the functions in a document by the model, published as
+//! diagnostics whose suggested fixes are offered as quickfixes.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tower_lsp::jsonrpc::Result;
+use tower_lsp::lsp_types::{
+    CodeAction, CodeActionKind, Diagnostic, DiagnosticSeverity, Position, Range, TextEdit, Url,
+    WorkspaceEdit,
+};
+
+use crate::code_action_providers::candidates::{self, CandidateSource};
+use crate::code_action_providers::parsed_document::{ts_language, ParsedDocument};
+use crate::code_action_providers::yaml::postprocess;
+use crate::configuration::ReviewConfig;
+use crate::llm_handlers::traits::Llm;
+use crate::logging::audit;
+
+/// The `source` of the diagnostics found by reviews.
+pub const SOURCE: &str = "polyglot_ls";
+/// The name the usage of reviews is recorded under.
+const ACTION_NAME: &str = "review";
+/// Reviewed functions remembered at most, all are forgotten beyond.
+const MAX_CACHED_FUNCTIONS: usize = 1000;
+
+/// The query capturing the functions of `language` unless one is configured.
+pub fn default_query(language: &str) -> Option<&'static str> {
+    match language {
+        "python" => Some("(function_definition) @function"),
+        "rust" => Some("(function_item) @function"),
+        "go" => Some("[(function_declaration) (method_declaration)] @function"),
+        _ => None,
+    }
+}
+
+/// A problem the model found in a function.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Finding {
+    /// The line, numbered as in the prompt while parsing and relative to the
+    /// first line of the function once cached.
+    pub line: usize,
+    #[serde(default)]
+    pub severity: Option<String>,
+    pub message: String,
+    /// The replacement of the whole line.
+    #[serde(default)]
+    pub fix: Option<String>,
+}
+
+/// The answer is either a list of findings or an object holding one.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Findings {
+    List(Vec<Finding>),
+    Object { findings: Vec<Finding> },
+}
+
+/// A function to review.
+struct Function {
+    /// The 0-based line the function starts at.
+    start_line: usize,
+    text: String,
+    /// Identifies the text, language and rules of the function.
+    hash: String,
+}
+
+/// The functions of `source` captured by `query`, without those nested in
+/// another one.
+fn functions(
+    source: &str,
+    uri: &Url,
+    language: &str,
+    query: &str,
+    rules: &[String],
+) -> Vec<Function> {
+    let doc = ParsedDocument::new(source, uri, language);
+    let root = doc.tree.root_node();
+    let mut nodes = doc.query(&root, query);
+    nodes.sort_by_key(|node| (node.start_byte(), std::cmp::Reverse(node.end_byte())));
+    let mut functions: Vec<Function> = Vec::new();
+    let mut end_byte = 0;
+    for node in nodes {
+        if node.start_byte() < end_byte {
+            continue;
+        }
+        end_byte = node.end_byte();
+        // Reviews of a function stay valid as long as its whole lines do.
+        let start_line = node.start_position().row;
+        let text = source
+            .lines()
+            .skip(start_line)
+            .take(node.end_position().row - start_line + 1)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let hash = Sha256::digest(format!("{language}\0{}\0{text}", rules.join("\0")).as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        functions.push(Function {
+            start_line,
+            text,
+            hash,
+        });
+    }
+    functions
+}
+
+fn prompt(language: &str, uri: &Url, rules: &[String], function: &Function) -> String {
+    let numbered: Vec<String> = function
+        .text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| format!("{:>5} | {line}", function.start_line + i + 1))
+        .collect();
+    let mut prompt = format!(
+        "Review the following {language} function from {uri} and report only real problems, \
+         like bugs, unhandled errors or misleading names.\n\n"
+    );
+    if !rules.is_empty() {
+        prompt.push_str("Also check these rules:\n");
+        for rule in rules {
+            prompt.push_str(&format!("- {rule}\n"));
+        }
+        prompt.push('\n');
+    }
+    prompt.push_str(&format!(
+        "```{language}\n{}\n```\n\n\
+         Answer with a JSON list and nothing else, like\n\
+         [{{\"line\": 12, \"severity\": \"warning\", \"message\": \"...\", \"fix\": \"...\"}}]\n\
+         where `line` is one of the line numbers before the `|`, `severity` is one of \
+         \"error\", \"warning\", \"information\" or \"hint\", and `fix` is the whole corrected \
+         line with its indentation, or null. Answer [] if there is nothing to report.",
+        numbered.join("\n")
+    ));
+    prompt
+}
+
+/// Parses the findings in `answer`, with the lines relative to `function`.
+fn parse_findings(answer: &str, function: &Function) -> anyhow::Result<Vec<Finding>> {
+    let answer = postprocess::strip_fences(answer);
+    let start = answer.find(['[', '{']).unwrap_or_default();
+    let end = answer.rfind([']', '}']).map_or(answer.len(), |end| end + 1);
+    let findings = match serde_json::from_str(answer.get(start..end).unwrap_or_default())? {
+        Findings::List(findings) | Findings::Object { findings } => findings,
+    };
+    let line_count = function.text.lines().count();
+    Ok(findings
+        .into_iter()
+        .filter_map(|finding| {
+            let line = finding.line.checked_sub(function.start_line + 1)?;
+            (line < line_count).then_some(Finding { line, ..finding })
+        })
+        .collect())
+}
+
+fn severity(name: Option<&str>) -> DiagnosticSeverity {
+    match name {
+        Some("error") => DiagnosticSeverity::ERROR,
+        Some("information") => DiagnosticSeverity::INFORMATION,
+        Some("hint") => DiagnosticSeverity::HINT,
+        _ => DiagnosticSeverity::WARNING,
+    }
+}
+
+/// The diagnostic of `finding` in `function`, spanning the whole line in
+/// UTF-8 byte columns.
+fn to_diagnostic(function: &Function, finding: &Finding) -> Diagnostic {
+    let line = function.text.lines().nth(finding.line).unwrap_or_default();
+    let row = (function.start_line + finding.line) as u32;
+    Diagnostic {
+        range: Range::new(Position::new(row, 0), Position::new(row, line.len() as u32)),
+        severity: Some(severity(finding.severity.as_deref())),
+        source: Some(SOURCE.to_owned()),
+        message: finding.message.clone(),
+        data: finding.fix.as_ref().map(|fix| json!({ "fix": fix })),
+        ..Default::default()
+    }
+}
+
+/// The quickfixes applying the fixes suggested by reviews among the
+/// `diagnostics` of `uri`.
+pub fn quickfixes(uri: &Url, diagnostics: &[Diagnostic]) -> Vec<CodeAction> {
+    diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.source.as_deref() == Some(SOURCE))
+        .filter_map(|diagnostic| {
+            let fix = diagnostic.data.as_ref()?.get("fix")?.as_str()?;
+            let edit = TextEdit {
+                range: diagnostic.range,
+                new_text: fix.to_owned(),
+            };
+            Some(CodeAction {
+                title: format!(
+                    "Polyglot: Fix \"{}\"",
+                    diagnostic.message.lines().next().unwrap_or_default()
+                ),
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diagnostic.clone()]),
+                edit: Some(WorkspaceEdit {
+                    changes: Some([(uri.clone(), vec![edit])].into_iter().collect()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// Reviews documents and remembers the findings of every function.
+#[derive(Debug, Default)]
+pub struct Reviewer {
+    /// The findings keyed by the hash of a function.
+    cache: Mutex<HashMap<String, Vec<Finding>>>,
+    /// The latest review scheduled per document.
+    generations: Mutex<HashMap<Url, u64>>,
+}
+
+impl Reviewer {
+    /// Schedules a review of `uri`, superseding those scheduled before, and
+    /// returns its generation.
+    pub fn schedule(&self, uri: &Url) -> u64 {
+        let mut generations = self.generations.lock().unwrap();
+        let generation = generations.entry(uri.clone()).or_default();
+        *generation += 1;
+        *generation
+    }
+
+    /// Returns true if no review of `uri` was scheduled after `generation`.
+    pub fn is_current(&self, uri: &Url, generation: u64) -> bool {
+        self.generations.lock().unwrap().get(uri) == Some(&generation)
+    }
+
+    /// Reviews the functions of `source`, asking the model only about those
+    /// that changed since they were last reviewed. The diagnostics are in
+    /// UTF-8 byte columns.
+    pub async fn review(
+        &self,
+        llm: &Llm,
+        config: &ReviewConfig,
+        uri: &Url,
+        source: &str,
+        language: &str,
+    ) -> Result<Vec<Diagnostic>> {
+        let query = config
+            .languages
+            .get(language)
+            .and_then(|language| language.query.clone())
+            .or_else(|| default_query(language).map(str::to_owned));
+        let (Some(query), Some(_)) = (query, ts_language(language)) else {
+            log::info!("cannot review {uri}, there is no function query for {language}");
+            return Ok(Vec::new());
+        };
+        let rules = config.rules(language);
+
+        let mut diagnostics = Vec::new();
+        let mut asked = 0;
+        for function in functions(source, uri, language, &query, &rules) {
+            let cached = self.cache.lock().unwrap().get(&function.hash).cloned();
+            let findings = match cached {
+                Some(findings) => findings,
+                None if asked >= config.max_functions => {
+                    log::info!("skipping a function of {uri}, {asked} were reviewed already");
+                    continue;
+                }
+                None => {
+                    asked += 1;
+                    let prompt = prompt(language, uri, &rules, &function);
+                    log::debug!("review prompt: {prompt}");
+                    let completion = candidates::complete(
+                        llm,
+                        &prompt,
+                        ACTION_NAME,
+                        1,
+                        CandidateSource::Requests,
+                    )
+                    .await?
+                    .remove(0);
+                    audit(
+                        ACTION_NAME,
+                        uri,
+                        &prompt,
+                        &completion.answer,
+                        None,
+                        completion.latency,
+                    );
+                    match parse_findings(&completion.answer.text, &function) {
+                        Ok(findings) => {
+                            let mut cache = self.cache.lock().unwrap();
+                            if cache.len() >= MAX_CACHED_FUNCTIONS {
+                                cache.clear();
+                            }
+                            cache.insert(function.hash.clone(), findings.clone());
+                            findings
+                        }
+                        Err(e) => {
+                            log::warn!("cannot parse the review of a function in {uri}: {e}");
+                            continue;
+                        }
+                    }
+                }
+            };
+            diagnostics.extend(
+                findings
+                    .iter()
+                    .map(|finding| to_diagnostic(&function, finding)),
+            );
+        }
+        Ok(diagnostics)
+    }
+}
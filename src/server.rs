@@ -1,11 +1,24 @@
-use crate::code_action_providers::{find_resolver, map_to_lsp};
-use crate::nonsense::{self, TextAdapter};
+use crate::code_action_providers::helper::internal_error;
+use crate::code_action_providers::traits::{ActionContext, PromptPreview};
+use crate::code_action_providers::{
+    builtin_providers, collect_code_actions, diagnostics_from_lsp, disable_actions, find_resolver,
+    is_named, load_providers, map_to_lsp, merge_providers, range_from_lsp, range_to_lsp, LoadError,
+    ProviderMap,
+};
+use crate::configuration::{ClientSettings, PolyglotConfig, ProjectConfig, ReviewConfig};
+use crate::llm_handlers::resilient::ResilientLLM;
+use crate::llm_handlers::traits::Llm;
+use crate::nonsense;
+use crate::preview::{self, PendingEdit, Previews};
+use crate::review::{self, Reviewer};
+use crate::usage::UsageTracker;
 
 use super::code_action_providers::parsed_document::ParsedDocument;
-use super::code_action_providers::traits::ActionProvider;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
 use tower_lsp::jsonrpc::{self, Result};
 use tower_lsp::lsp_types::*;
@@ -23,7 +36,59 @@ pub struct ResolveAction<T> {
     pub id: String,
 }
 
-pub(crate) struct Backend {
+/// Shows the prompts of the code actions at a range without calling the model.
+pub const SHOW_PROMPT_COMMAND: &str = "polyglot.showPrompt";
+/// Returns the tokens spent per day, model and action.
+pub const USAGE_STATS_COMMAND: &str = "polyglot.usageStats";
+/// Applies the pending edit of a preview document.
+pub const APPLY_PREVIEW_COMMAND: &str = "polyglot.applyPreview";
+/// Drops the pending edit of a preview document.
+pub const DISCARD_PREVIEW_COMMAND: &str = "polyglot.discardPreview";
+/// Returns the diff shown by a preview document.
+pub const PREVIEW_CONTENT_COMMAND: &str = "polyglot.previewContent";
+/// Reviews a document right away and publishes the findings.
+pub const REVIEW_COMMAND: &str = "polyglot.review";
+/// The id of the registration watching the code action configs.
+const CONFIG_WATCHER_ID: &str = "polyglot-config-watcher";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReviewArgs {
+    pub text_document: TextDocumentIdentifier,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewArgs {
+    /// The `polyglot-preview:` URI of the preview document.
+    pub uri: Url,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UsageStatsArgs {
+    /// Number of days to return, including today.
+    pub days: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShowPromptArgs {
+    pub text_document: TextDocumentIdentifier,
+    pub range: Range,
+    /// Only show the prompt of the action with this name.
+    pub action: Option<String>,
+    /// Diagnostics at the range, as in `CodeActionContext`.
+    #[serde(default)]
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// The text and language of a document opened in the editor.
+#[derive(Debug, Clone)]
+pub struct OpenDocument {
+    pub text: String,
+    pub language: String,
+}
+
+pub struct Backend {
     /// The client used for communicating with the backend.
     pub client: Client,
     /// The current text being processed.
@@ -31,12 +96,85 @@ pub(crate) struct Backend {
     /// The current language being processed.
     pub current_language: Arc<RwLock<String>>,
     /// A map of action providers, keyed by the name of the provider.
-    pub providers: HashMap<String, Vec<Box<dyn ActionProvider>>>,
+    ///
+    /// The inner map is replaced as a whole on reload, so requests in flight
+    /// keep using the snapshot they started with.
+    pub providers: Arc<RwLock<Arc<ProviderMap>>>,
     /// The parsed document being processed.
     pub parsed_doc: ParsedDocument,
 
     pub indexed_text: Arc<RwLock<nonsense::IndexedText<String>>>,
     // translation: Translation,
+    /// The directory containing the per-language code action configs.
+    pub code_actions_config_dir: PathBuf,
+    /// The LLM handed to every provider created on (re)load.
+    pub prompt_handler: Arc<RwLock<Arc<Llm>>>,
+    /// Whether the client lets us register file watchers dynamically.
+    pub can_watch_files: Arc<RwLock<bool>>,
+    /// The server configuration.
+    pub polyglot_config: Arc<RwLock<PolyglotConfig>>,
+    /// The workspace folders announced by the client.
+    pub workspace_folders: Arc<RwLock<Vec<PathBuf>>>,
+    /// Edits shown as a diff and waiting to be applied or discarded.
+    pub previews: Arc<RwLock<Previews>>,
+    /// Reviews documents and remembers the findings per function.
+    pub reviewer: Arc<Reviewer>,
+    /// All documents opened in the editor, keyed by their URI.
+    pub documents: Arc<RwLock<HashMap<Url, OpenDocument>>>,
+    /// Records the tokens spent, if usage tracking is enabled.
+    pub usage: Option<Arc<UsageTracker>>,
+    /// The code action config files seen by the last reload, compared on every
+    /// code action request if the client cannot watch files.
+    pub config_files: Arc<RwLock<ConfigFiles>>,
+    /// The directories the client watches for changes, `None` until the
+    /// watchers are registered in `initialized`.
+    pub watching_dirs: Arc<RwLock<Option<Vec<PathBuf>>>>,
+}
+
+/// A review of a document, detached from the backend so it can run in the
+/// background.
+struct ReviewJob {
+    client: Client,
+    reviewer: Arc<Reviewer>,
+    llm: Arc<Llm>,
+    config: ReviewConfig,
+    uri: Url,
+    source: String,
+    language: String,
+    index: nonsense::IndexedText<String>,
+    generation: u64,
+}
+
+impl ReviewJob {
+    /// Reviews the document and publishes the findings, unless another review
+    /// of it was scheduled in the meantime.
+    async fn run(self) -> Result<Vec<Diagnostic>> {
+        let diagnostics = self
+            .reviewer
+            .review(
+                &self.llm,
+                &self.config,
+                &self.uri,
+                &self.source,
+                &self.language,
+            )
+            .await?;
+        let diagnostics: Vec<Diagnostic> = diagnostics
+            .into_iter()
+            .filter_map(|diagnostic| {
+                Some(Diagnostic {
+                    range: range_to_lsp(&diagnostic.range, &self.index)?,
+                    ..diagnostic
+                })
+            })
+            .collect();
+        if self.reviewer.is_current(&self.uri, self.generation) {
+            self.client
+                .publish_diagnostics(self.uri, diagnostics.clone(), None)
+                .await;
+        }
+        Ok(diagnostics)
+    }
 }
 
 impl std::fmt::Debug for Backend {
@@ -48,6 +186,507 @@ impl std::fmt::Debug for Backend {
     }
 }
 
+impl Backend {
+    pub fn new(
+        client: Client,
+        code_actions_config_dir: PathBuf,
+        prompt_handler: Arc<Llm>,
+        polyglot_config: PolyglotConfig,
+        usage: Option<Arc<UsageTracker>>,
+    ) -> Self {
+        Self {
+            client,
+            current_text: Arc::new("".to_string().into()),
+            current_language: Arc::new("".to_string().into()),
+            providers: Default::default(),
+            parsed_doc: ParsedDocument::new("", &Url::parse("http://example.com").unwrap(), ""),
+            indexed_text: Arc::new(RwLock::new(nonsense::IndexedText::new("".to_owned()))),
+            code_actions_config_dir,
+            prompt_handler: Arc::new(RwLock::new(prompt_handler)),
+            can_watch_files: Default::default(),
+            polyglot_config: Arc::new(RwLock::new(polyglot_config)),
+            workspace_folders: Default::default(),
+            previews: Default::default(),
+            reviewer: Default::default(),
+            documents: Default::default(),
+            config_files: Default::default(),
+            watching_dirs: Default::default(),
+            usage,
+        }
+    }
+
+    /// Returns the currently active providers.
+    fn providers(&self) -> Arc<ProviderMap> {
+        self.providers.read().unwrap().clone()
+    }
+
+    /// Lets the user choose one of the resolved `candidates` of `action`.
+    /// Without candidates, or if the user declines, nothing is edited.
+    async fn choose_candidate(
+        &self,
+        action: &CodeAction,
+        mut candidates: Vec<CodeAction>,
+    ) -> CodeAction {
+        if candidates.len() <= 1 {
+            return candidates.pop().unwrap_or_else(nop_codeaction);
+        }
+        let items = candidates
+            .iter()
+            .map(|candidate| MessageActionItem {
+                title: candidate.title.clone(),
+                properties: Default::default(),
+            })
+            .collect();
+        let chosen = self
+            .client
+            .show_message_request(
+                MessageType::INFO,
+                format!("{}: choose a candidate", action.title),
+                Some(items),
+            )
+            .await;
+        match chosen {
+            Ok(Some(item)) => candidates
+                .into_iter()
+                .find(|candidate| candidate.title == item.title)
+                .unwrap_or_else(nop_codeaction),
+            Ok(None) => nop_codeaction(),
+            Err(e) => {
+                log::warn!("cannot ask the user to choose a candidate: {e}");
+                nop_codeaction()
+            }
+        }
+    }
+
+    /// Returns true if the edit of `action` should be reviewed before it is
+    /// applied.
+    fn wants_preview(&self, action: &CodeAction) -> bool {
+        let config = self.polyglot_config.read().unwrap();
+        config.preview.enabled
+            && (config.preview.actions.is_empty()
+                || config
+                    .preview
+                    .actions
+                    .iter()
+                    .any(|name| is_named(action, name)))
+    }
+
+    /// Stores the edit of the `resolved` action on `document` and opens its
+    /// `diff` in the editor. The returned action edits nothing, the edit is
+    /// applied later by `polyglot.applyPreview`. Editors that cannot open the
+    /// preview document are asked to apply or discard the diff right away.
+    async fn show_preview(&self, resolved: CodeAction, document: Url, diff: String) -> CodeAction {
+        let Some(edit) = resolved.edit.clone() else {
+            return resolved;
+        };
+        let uri = self.previews.write().unwrap().insert(PendingEdit {
+            title: resolved.title.clone(),
+            document,
+            edit,
+            diff,
+        });
+        let shown = self
+            .client
+            .show_document(ShowDocumentParams {
+                uri: uri.clone(),
+                external: Some(false),
+                take_focus: Some(true),
+                selection: None,
+            })
+            .await;
+        if !matches!(shown, Ok(true)) {
+            log::info!("the client did not show {uri}, asking instead: {shown:?}");
+            return self.confirm_preview(resolved, &uri).await;
+        }
+        self.client
+            .show_message(
+                MessageType::INFO,
+                format!(
+                    "{}: review {uri}, then run {APPLY_PREVIEW_COMMAND} or {DISCARD_PREVIEW_COMMAND}",
+                    resolved.title
+                ),
+            )
+            .await;
+        CodeAction {
+            edit: None,
+            ..resolved
+        }
+    }
+
+    /// Shows the diff of the preview document `uri` in a message and returns
+    /// the `resolved` action with its edit only if the user applies it.
+    async fn confirm_preview(&self, resolved: CodeAction, uri: &Url) -> CodeAction {
+        let Some(pending) = self.previews.write().unwrap().remove(uri) else {
+            return nop_codeaction();
+        };
+        let items = ["Apply", "Discard"]
+            .into_iter()
+            .map(|title| MessageActionItem {
+                title: title.to_owned(),
+                properties: Default::default(),
+            })
+            .collect();
+        let chosen = self
+            .client
+            .show_message_request(
+                MessageType::INFO,
+                format!("{}:\n{}", pending.title, pending.diff),
+                Some(items),
+            )
+            .await;
+        match chosen {
+            Ok(Some(item)) if item.title == "Apply" => resolved,
+            Ok(_) => CodeAction {
+                edit: None,
+                ..resolved
+            },
+            Err(e) => {
+                log::warn!("cannot ask the user to apply {}: {e}", pending.title);
+                CodeAction {
+                    edit: None,
+                    ..resolved
+                }
+            }
+        }
+    }
+
+    /// Sends the pending edit of the preview document `uri` to the client.
+    async fn apply_preview(&self, uri: &Url) -> Result<bool> {
+        let pending = self.previews.write().unwrap().remove(uri).ok_or_else(|| {
+            jsonrpc::Error::invalid_params(format!(
+                "no pending edit for {uri}, it was applied, discarded or its document changed or closed"
+            ))
+        })?;
+        let response = self
+            .client
+            .send_request::<request::ApplyWorkspaceEdit>(ApplyWorkspaceEditParams {
+                edit: pending.edit,
+                label: Some(pending.title.clone()),
+            })
+            .await?;
+        if !response.applied {
+            self.client
+                .show_message(
+                    MessageType::WARNING,
+                    format!(
+                        "{}: the editor did not apply the edit: {}",
+                        pending.title,
+                        response.failure_reason.unwrap_or_default()
+                    ),
+                )
+                .await;
+        }
+        Ok(response.applied)
+    }
+
+    /// Schedules a review of the text of `uri`, or returns `None` if the
+    /// document is not open.
+    fn review_job(&self, uri: Url) -> Option<ReviewJob> {
+        let document = self.documents.read().unwrap().get(&uri)?.clone();
+        Some(ReviewJob {
+            client: self.client.clone(),
+            reviewer: self.reviewer.clone(),
+            llm: self.prompt_handler(),
+            config: self.polyglot_config.read().unwrap().review.clone(),
+            generation: self.reviewer.schedule(&uri),
+            uri,
+            index: nonsense::IndexedText::new(document.text.clone()),
+            source: document.text,
+            language: document.language,
+        })
+    }
+
+    /// Returns the LLM used for newly created providers.
+    fn prompt_handler(&self) -> Arc<Llm> {
+        self.prompt_handler.read().unwrap().clone()
+    }
+
+    /// Applies client settings and rebuilds everything that depends on them.
+    ///
+    /// The Bedrock client is only re-created if the model config changed. A
+    /// mock LLM is kept as is.
+    async fn apply_client_settings(&self, settings: ClientSettings) {
+        let (old_model, new_model) = {
+            let mut config = self.polyglot_config.write().unwrap();
+            let old_model = config.model.clone();
+            config.apply(settings);
+            (old_model, config.model.clone())
+        };
+
+        let prompt_handler = self.prompt_handler();
+        if old_model != new_model && prompt_handler.is_bedrock() {
+            match ResilientLLM::from_config(&new_model).await {
+                Ok(bedrock) => {
+                    *self.prompt_handler.write().unwrap() =
+                        prompt_handler.with_backend(Arc::new(Llm::Resilient(bedrock)));
+                }
+                Err(e) => {
+                    self.client
+                        .show_message(
+                            MessageType::ERROR,
+                            format!("Cannot create model {}: {e}", new_model.bedrock.model_id),
+                        )
+                        .await;
+                }
+            }
+        }
+        self.reload_providers().await;
+    }
+
+    /// Returns the `.polyglot_ls` directories of all workspace folders and
+    /// whether the user trusts them.
+    fn project_dirs(&self) -> Vec<(PathBuf, bool)> {
+        let config = self.polyglot_config.read().unwrap();
+        self.workspace_folders
+            .read()
+            .unwrap()
+            .iter()
+            .map(|folder| folder.join(ProjectConfig::DIR_NAME))
+            .filter(|project_dir| project_dir.is_dir())
+            .map(|project_dir| {
+                let trusted = project_dir
+                    .parent()
+                    .map(|folder| config.is_trusted_project(folder))
+                    .unwrap_or(false);
+                (project_dir, trusted)
+            })
+            .collect()
+    }
+
+    /// Rebuilds all providers from disk and swaps them in.
+    ///
+    /// User-level actions are loaded first, and actions from trusted projects
+    /// are layered on top. Open documents are not touched. Files that fail to
+    /// load are reported to the user and left out of the new provider set.
+    async fn reload_providers(&self) {
+        *self.config_files.write().unwrap() = config_files(&self.watched_dirs());
+        let prompt_handler = self.prompt_handler();
+        let mut providers = builtin_providers(prompt_handler.clone());
+        let (user_providers, mut errors) =
+            load_providers(self.code_actions_config_dir.clone(), prompt_handler.clone());
+        merge_providers(&mut providers, user_providers);
+
+        for (project_dir, trusted) in self.project_dirs() {
+            if !trusted {
+                self.client
+                    .show_message(
+                        MessageType::WARNING,
+                        format!(
+                            "Ignoring code actions in {}. Add {} to `trusted_projects` in the server config to load them.",
+                            project_dir.display(),
+                            project_dir.parent().unwrap_or(&project_dir).display()
+                        ),
+                    )
+                    .await;
+                continue;
+            }
+            let (project_providers, project_errors) =
+                load_providers(project_dir.join("code_actions"), prompt_handler.clone());
+            merge_providers(&mut providers, project_providers);
+            errors.extend(project_errors);
+
+            let project_config_path = project_dir.join("config.yaml");
+            if project_config_path.exists() {
+                match ProjectConfig::try_read_from_file(&project_config_path) {
+                    Ok(project_config) => {
+                        disable_actions(&mut providers, &project_config.disabled_actions)
+                    }
+                    Err(e) => errors.push(LoadError {
+                        path: project_config_path,
+                        message: e.to_string(),
+                    }),
+                }
+            }
+        }
+
+        let disabled_actions = self
+            .polyglot_config
+            .read()
+            .unwrap()
+            .disabled_actions
+            .clone();
+        disable_actions(&mut providers, &disabled_actions);
+
+        let num_actions: usize = providers.values().map(|p| p.len()).sum();
+        *self.providers.write().unwrap() = Arc::new(providers);
+
+        self.client
+            .log_message(
+                MessageType::INFO,
+                format!("loaded {num_actions} code actions"),
+            )
+            .await;
+        for error in errors {
+            self.client
+                .show_message(MessageType::WARNING, format!("Cannot load {error}"))
+                .await;
+        }
+
+        // A project trusted or a workspace folder added since changes the
+        // directories to watch.
+        let is_watching = self.watching_dirs.read().unwrap().is_some();
+        if is_watching {
+            self.watch_config_files().await;
+        }
+    }
+
+    /// The directories holding the code actions, the user-level one and those
+    /// of trusted projects.
+    fn watched_dirs(&self) -> Vec<PathBuf> {
+        let mut watched_dirs = vec![self.code_actions_config_dir.clone()];
+        watched_dirs.extend(
+            self.project_dirs()
+                .into_iter()
+                .filter(|(_, trusted)| *trusted)
+                .map(|(project_dir, _)| project_dir),
+        );
+        watched_dirs
+    }
+
+    /// Reloads the code actions if one of their config files was added,
+    /// removed or modified since the last reload. Stands in for file watching
+    /// on clients that cannot do it.
+    async fn reload_if_changed(&self) {
+        let files = config_files(&self.watched_dirs());
+        let changed = *self.config_files.read().unwrap() != files;
+        if changed {
+            log::info!("code action configs changed, reloading");
+            self.reload_providers().await;
+        }
+    }
+
+    /// Asks the client to notify us about changes in the code action configs,
+    /// replacing the watchers of the previous call if the directories changed.
+    async fn watch_config_files(&self) {
+        let dirs = self.watched_dirs();
+        // Swapped before asking the client, so a reload meanwhile compares
+        // with these directories.
+        let registered = self.watching_dirs.write().unwrap().replace(dirs.clone());
+        if registered.as_ref() == Some(&dirs) {
+            return;
+        }
+        if registered.is_some() {
+            let unregistration = Unregistration {
+                id: CONFIG_WATCHER_ID.to_owned(),
+                method: "workspace/didChangeWatchedFiles".to_owned(),
+            };
+            if let Err(e) = self
+                .client
+                .unregister_capability(vec![unregistration])
+                .await
+            {
+                log::warn!("cannot unregister the code action config watchers: {e}");
+            }
+        }
+        let watchers = dirs
+            .iter()
+            .map(|dir| FileSystemWatcher {
+                glob_pattern: GlobPattern::String(format!("{}/**/*.{{lua,yaml}}", dir.display())),
+                kind: None,
+            })
+            .collect();
+        let registration = Registration {
+            id: CONFIG_WATCHER_ID.to_owned(),
+            method: "workspace/didChangeWatchedFiles".to_owned(),
+            register_options: Some(
+                serde_json::to_value(DidChangeWatchedFilesRegistrationOptions { watchers })
+                    .unwrap(),
+            ),
+        };
+        if let Err(e) = self.client.register_capability(vec![registration]).await {
+            self.client
+                .log_message(
+                    MessageType::WARNING,
+                    format!("cannot watch code action configs: {e}"),
+                )
+                .await;
+        }
+    }
+
+    /// Builds the prompts of the code actions offered at `args.range`, which
+    /// are also written to the client log.
+    async fn show_prompt(&self, args: ShowPromptArgs) -> Result<Vec<PromptPreview>> {
+        let source = self.current_text.read().unwrap().clone();
+        let lang = self.current_language.read().unwrap().clone();
+        let index = self.indexed_text.read().unwrap().clone();
+        let doc = ParsedDocument::new(&source, &args.text_document.uri, &lang);
+        let range = range_from_lsp(&args.range, &index).ok_or(jsonrpc::Error::invalid_params(
+            "range is outside of the document",
+        ))?;
+
+        let diagnostics = diagnostics_from_lsp(args.diagnostics, &index);
+
+        let providers = self.providers();
+        let mut previews = Vec::new();
+        for action in collect_code_actions(&providers, &doc, &lang, &range, &diagnostics) {
+            if let Some(action_name) = &args.action {
+                if !is_named(&action, action_name) {
+                    continue;
+                }
+            }
+            let Some(args) = action
+                .data
+                .clone()
+                .and_then(|data| serde_json::from_value::<ResolveActionKind>(data).ok())
+            else {
+                continue;
+            };
+            let Some(provider) = find_resolver(&providers, &args.id, &lang) else {
+                continue;
+            };
+            let mut preview = provider.preview(&doc, &action)?;
+            preview.placement_range = preview
+                .placement_range
+                .and_then(|range| range_to_lsp(&range, &index));
+            if let Some(node) = preview.context_node.as_mut() {
+                node.range = range_to_lsp(&node.range, &index).unwrap_or(node.range);
+            }
+            previews.push(preview);
+        }
+
+        for preview in previews.iter() {
+            self.client
+                .log_message(
+                    MessageType::INFO,
+                    format!("Prompt of {}:\n{}", preview.action, preview.prompt),
+                )
+                .await;
+        }
+        Ok(previews)
+    }
+}
+
+fn is_code_action_config(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext == "lua" || ext == "yaml")
+        .unwrap_or(false)
+}
+
+/// Code action config files and the time they were last modified.
+pub type ConfigFiles = Vec<(PathBuf, Option<SystemTime>)>;
+
+/// The code action config files below `dirs`, sorted.
+fn config_files(dirs: &[PathBuf]) -> ConfigFiles {
+    let mut files = Vec::new();
+    let mut pending = dirs.to_vec();
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if is_code_action_config(&path) {
+                let modified = entry.metadata().and_then(|m| m.modified()).ok();
+                files.push((path, modified));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
 pub fn nop_codeaction() -> CodeAction {
     CodeAction {
         title: "cancel".to_owned(),
@@ -61,9 +700,72 @@ pub fn nop_codeaction() -> CodeAction {
     }
 }
 
+/// The actions offered in the preview document `uri`.
+fn preview_actions(uri: &Url) -> Vec<CodeActionOrCommand> {
+    let args = vec![serde_json::json!({ "uri": uri })];
+    [
+        ("Polyglot: Apply Edit", APPLY_PREVIEW_COMMAND),
+        ("Polyglot: Discard Edit", DISCARD_PREVIEW_COMMAND),
+    ]
+    .into_iter()
+    .map(|(title, command)| {
+        CodeActionOrCommand::CodeAction(CodeAction {
+            title: title.to_owned(),
+            command: Some(Command {
+                title: title.to_owned(),
+                command: command.to_owned(),
+                arguments: Some(args.clone()),
+            }),
+            ..nop_codeaction()
+        })
+    })
+    .collect()
+}
+
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
-    async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
+    async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
+        let can_watch_files = params
+            .capabilities
+            .workspace
+            .and_then(|w| w.did_change_watched_files)
+            .and_then(|w| w.dynamic_registration)
+            .unwrap_or(false);
+        *self.can_watch_files.write().unwrap() = can_watch_files;
+
+        let workspace_folders: Vec<PathBuf> = match params.workspace_folders {
+            Some(folders) => folders
+                .iter()
+                .filter_map(|folder| folder.uri.to_file_path().ok())
+                .collect(),
+            None => params
+                .root_uri
+                .and_then(|uri| uri.to_file_path().ok())
+                .into_iter()
+                .collect(),
+        };
+        *self.workspace_folders.write().unwrap() = workspace_folders;
+
+        // Loaded before answering, so the first code action request already
+        // finds the providers.
+        let settings = match params.initialization_options.map(ClientSettings::from_json) {
+            Some(Ok(settings)) => Some(settings),
+            Some(Err(e)) => {
+                self.client
+                    .show_message(
+                        MessageType::WARNING,
+                        format!("Ignoring invalid initializationOptions: {e}"),
+                    )
+                    .await;
+                None
+            }
+            None => None,
+        };
+        match settings {
+            Some(settings) => self.apply_client_settings(settings).await,
+            None => self.reload_providers().await,
+        }
+
         Ok(InitializeResult {
             server_info: Some(ServerInfo {
                 name: "PolyglotLS".to_string(),
@@ -87,6 +789,26 @@ impl LanguageServer for Backend {
                         },
                     },
                 )),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        SHOW_PROMPT_COMMAND.to_owned(),
+                        USAGE_STATS_COMMAND.to_owned(),
+                        APPLY_PREVIEW_COMMAND.to_owned(),
+                        DISCARD_PREVIEW_COMMAND.to_owned(),
+                        PREVIEW_CONTENT_COMMAND.to_owned(),
+                        REVIEW_COMMAND.to_owned(),
+                    ],
+                    work_done_progress_options: WorkDoneProgressOptions {
+                        work_done_progress: None,
+                    },
+                }),
+                workspace: Some(WorkspaceServerCapabilities {
+                    workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                        supported: Some(true),
+                        change_notifications: Some(OneOf::Left(true)),
+                    }),
+                    file_operations: None,
+                }),
                 ..ServerCapabilities::default()
             },
         })
@@ -105,6 +827,10 @@ impl LanguageServer for Backend {
         self.client
             .log_message(MessageType::INFO, "initialized!")
             .await;
+        let can_watch_files = *self.can_watch_files.read().unwrap();
+        if can_watch_files {
+            self.watch_config_files().await;
+        }
     }
 
     async fn shutdown(&self) -> Result<()> {
@@ -121,8 +847,11 @@ impl LanguageServer for Backend {
     ///
     /// The resolved code action.
     async fn code_action_resolve(&self, action: CodeAction) -> Result<CodeAction> {
-        //log::info!("code_action_resolve {:?}", action);
-        let json_args = action.data.clone().unwrap();
+        log::debug!("code_action_resolve {:?}", action);
+        // The apply and discard actions of preview documents are commands.
+        let Some(json_args) = action.data.clone() else {
+            return Ok(action);
+        };
         let args = serde_json::from_value::<ResolveActionKind>(json_args.clone()).unwrap();
 
         let source = self.current_text.read().unwrap().clone();
@@ -130,23 +859,54 @@ impl LanguageServer for Backend {
         let parsed_doc = ParsedDocument::new(&source, &self.parsed_doc.uri, &lang);
         let index = self.indexed_text.read().unwrap().clone();
 
-        let provider = find_resolver(&self.providers, &args.id, &lang);
+        let providers = self.providers();
+        let provider = find_resolver(&providers, &args.id, &lang);
         if provider.is_none() {
             return Err(jsonrpc::Error::new(jsonrpc::ErrorCode::ServerError(1)));
         }
 
-        let code_action = provider
+        let candidates = provider
             .unwrap()
-            .on_resolve(&parsed_doc, action.clone())
+            .on_resolve_candidates(&parsed_doc, action.clone())
             .await;
 
-        match code_action {
-            Ok(mut c) => {
-                map_to_lsp(&mut c, &index);
-                Ok(c)
+        if let Some(warning) = self
+            .usage
+            .as_ref()
+            .and_then(|usage| usage.take_budget_warning())
+        {
+            self.client
+                .show_message(MessageType::WARNING, warning)
+                .await;
+        }
+
+        // Actions without a placement are cancelled silently, but failures
+        // of the model would otherwise go unnoticed in many editors.
+        if let Err(e) = &candidates {
+            if e.code == jsonrpc::ErrorCode::InternalError {
+                self.client
+                    .show_message(
+                        MessageType::ERROR,
+                        format!("{}: {}", action.title, e.message),
+                    )
+                    .await;
             }
-            Err(_) => Err(jsonrpc::Error::new(jsonrpc::ErrorCode::ServerError(1))),
         }
+
+        let mut c = self.choose_candidate(&action, candidates?).await;
+        // The diff is rendered before the ranges are converted to UTF-16.
+        let preview = serde_json::from_value::<ResolveAction<ActionContext>>(json_args)
+            .ok()
+            .filter(|_| c.edit.is_some() && self.wants_preview(&action))
+            .map(|args| {
+                let diff = preview::render_diff(&source, &args.data.uri, &c);
+                (args.data.uri, diff)
+            });
+        map_to_lsp(&mut c, &index);
+        if let Some((document, diff)) = preview {
+            return Ok(self.show_preview(c, document, diff).await);
+        }
+        Ok(c)
     }
 
     /// Provide code actions for the current document.
@@ -164,58 +924,197 @@ impl LanguageServer for Backend {
             .await;
 
         let uri = &params.text_document.uri;
+        if preview::is_preview(uri) {
+            return Ok(Some(preview_actions(uri)));
+        }
+        let can_watch_files = *self.can_watch_files.read().unwrap();
+        if !can_watch_files {
+            self.reload_if_changed().await;
+        }
         let source = self.current_text.read().unwrap().clone();
         let lang = self.current_language.read().unwrap().clone();
+        if !self
+            .polyglot_config
+            .read()
+            .unwrap()
+            .is_language_enabled(&lang)
+        {
+            return Ok(Some(vec![]));
+        }
         let index = self.indexed_text.read().unwrap().clone();
         let doc = ParsedDocument::new(&source, uri, &lang);
 
         // LSP is UTF16, our abckend is UTF8
-        let lsp_range = params.range;
-        let rs = index.lsp_range_to_range(&lsp_range);
-        if let Some(rs) = rs {
-            let fake_lsp_range = Range {
-                start: Position {
-                    line: rs.start.line,
-                    character: rs.start.col,
-                },
-                end: Position {
-                    line: rs.end.line,
-                    character: rs.end.col,
-                },
-            };
+        if let Some(fake_lsp_range) = range_from_lsp(&params.range, &index) {
+            let review_fixes = review::quickfixes(uri, &params.context.diagnostics);
+            let diagnostics = diagnostics_from_lsp(params.context.diagnostics, &index);
+            let providers = self.providers();
+            let actions =
+                collect_code_actions(&providers, &doc, &lang, &fake_lsp_range, &diagnostics)
+                    .into_iter()
+                    .map(|mut action| {
+                        // Clients match the diagnostics a quickfix resolves by range.
+                        for diagnostic in action.diagnostics.iter_mut().flatten() {
+                            diagnostic.range =
+                                range_to_lsp(&diagnostic.range, &index).unwrap_or(diagnostic.range);
+                        }
+                        action
+                    });
+            let actions = review_fixes
+                .into_iter()
+                .chain(actions)
+                .map(CodeActionOrCommand::CodeAction)
+                .collect();
 
-            let mut actions = vec![];
-            if let Some(language_specific_providers) = self.providers.get(&lang) {
-                for provider in language_specific_providers.iter() {
-                    if let Some(action) = provider.create_code_action(&doc, &fake_lsp_range) {
-                        actions.push(CodeActionOrCommand::CodeAction(action));
-                    }
-                }
+            Ok(Some(actions))
+        } else {
+            Err(jsonrpc::Error::new(jsonrpc::ErrorCode::ServerError(1)))
+        }
+    }
+
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> Result<Option<serde_json::Value>> {
+        match params.command.as_str() {
+            SHOW_PROMPT_COMMAND => {
+                let args = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value::<ShowPromptArgs>(arg).ok())
+                    .ok_or(jsonrpc::Error::invalid_params(
+                        "expected { textDocument, range, action?, diagnostics? }",
+                    ))?;
+                let previews = self.show_prompt(args).await?;
+                Ok(Some(serde_json::json!(previews)))
             }
-            if let Some(language_specific_providers) = self.providers.get("__all__") {
-                for provider in language_specific_providers.iter() {
-                    if let Some(action) = provider.create_code_action(&doc, &fake_lsp_range) {
-                        actions.push(CodeActionOrCommand::CodeAction(action));
+            USAGE_STATS_COMMAND => {
+                let args = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value::<UsageStatsArgs>(arg).ok())
+                    .unwrap_or_default();
+                let usage = self.usage.as_ref().ok_or(jsonrpc::Error::invalid_params(
+                    "token usage is not tracked, enable it with `usage.enabled`",
+                ))?;
+                let summary = usage
+                    .summary(args.days.unwrap_or(7))
+                    .map_err(internal_error)?;
+                Ok(Some(serde_json::json!(summary)))
+            }
+            APPLY_PREVIEW_COMMAND | DISCARD_PREVIEW_COMMAND | PREVIEW_CONTENT_COMMAND => {
+                let args = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value::<PreviewArgs>(arg).ok())
+                    .ok_or(jsonrpc::Error::invalid_params("expected { uri }"))?;
+                match params.command.as_str() {
+                    APPLY_PREVIEW_COMMAND => {
+                        let applied = self.apply_preview(&args.uri).await?;
+                        Ok(Some(serde_json::json!(applied)))
+                    }
+                    DISCARD_PREVIEW_COMMAND => {
+                        let discarded = self.previews.write().unwrap().remove(&args.uri);
+                        Ok(Some(serde_json::json!(discarded.is_some())))
+                    }
+                    _ => {
+                        let previews = self.previews.read().unwrap();
+                        let pending =
+                            previews
+                                .get(&args.uri)
+                                .ok_or(jsonrpc::Error::invalid_params(
+                                    "no pending edit for this uri",
+                                ))?;
+                        Ok(Some(serde_json::json!(pending.diff)))
                     }
                 }
             }
-
-            Ok(Some(actions))
-        } else {
-            Err(jsonrpc::Error::new(jsonrpc::ErrorCode::ServerError(1)))
+            REVIEW_COMMAND => {
+                let args = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value::<ReviewArgs>(arg).ok())
+                    .ok_or(jsonrpc::Error::invalid_params("expected { textDocument }"))?;
+                let uri = args.text_document.uri;
+                let job = self
+                    .review_job(uri.clone())
+                    .ok_or_else(|| jsonrpc::Error::invalid_params(format!("{uri} is not open")))?;
+                let diagnostics = job.run().await?;
+                Ok(Some(serde_json::json!(diagnostics)))
+            }
+            _ => Err(jsonrpc::Error::method_not_found()),
         }
     }
 
-    async fn did_change_configuration(&self, _: DidChangeConfigurationParams) {
+    /// Apply settings pushed by the client to the running server.
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
         self.client
             .log_message(MessageType::INFO, "configuration changed!")
             .await;
+        match ClientSettings::from_json(params.settings) {
+            Ok(settings) => self.apply_client_settings(settings).await,
+            Err(e) => {
+                self.client
+                    .show_message(
+                        MessageType::WARNING,
+                        format!("Ignoring invalid settings: {e}"),
+                    )
+                    .await
+            }
+        }
     }
 
-    async fn did_change_watched_files(&self, _: DidChangeWatchedFilesParams) {
+    /// Reload the code actions with those of the projects in the new folders.
+    async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
+        {
+            let mut folders = self.workspace_folders.write().unwrap();
+            let removed: Vec<PathBuf> = params
+                .event
+                .removed
+                .iter()
+                .filter_map(|folder| folder.uri.to_file_path().ok())
+                .collect();
+            folders.retain(|folder| !removed.contains(folder));
+            folders.extend(
+                params
+                    .event
+                    .added
+                    .iter()
+                    .filter_map(|folder| folder.uri.to_file_path().ok()),
+            );
+        }
+        self.reload_providers().await;
+    }
+
+    /// Reload all code actions when one of their config files changed.
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
         self.client
             .log_message(MessageType::INFO, "watched files have changed!")
             .await;
+        let changed: Vec<String> = params
+            .changes
+            .iter()
+            .filter_map(|change| change.uri.to_file_path().ok())
+            .filter(|path| is_code_action_config(path))
+            .map(|path| path.display().to_string())
+            .collect();
+        if changed.is_empty() {
+            return;
+        }
+        self.reload_providers().await;
+        self.client
+            .show_message(
+                MessageType::INFO,
+                format!(
+                    "Reloaded code actions after changes to {}",
+                    changed.join(", ")
+                ),
+            )
+            .await;
     }
 
     /// Handle a text document open notification.
@@ -228,10 +1127,12 @@ impl LanguageServer for Backend {
     ///
     /// This function does not return a value.
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        //log::info!("did_open");
         self.client
             .log_message(MessageType::INFO, "file opened!")
             .await;
+        if preview::is_preview(&params.text_document.uri) {
+            return;
+        }
         let mut src = self.current_text.write().unwrap();
         *src = params.text_document.text.clone();
         let mut src = self.current_language.write().unwrap();
@@ -239,17 +1140,43 @@ impl LanguageServer for Backend {
 
         let mut src = self.indexed_text.write().unwrap();
         *src = nonsense::IndexedText::new(params.text_document.text.to_owned());
-        //log::info!("set language to {}", &params.text_document.language_id);
+        self.documents.write().unwrap().insert(
+            params.text_document.uri,
+            OpenDocument {
+                text: params.text_document.text,
+                language: params.text_document.language_id.clone(),
+            },
+        );
+        log::debug!("set language to {}", &params.text_document.language_id);
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
         self.client
             .log_message(MessageType::INFO, "file changed!")
             .await;
+        if preview::is_preview(&params.text_document.uri) {
+            return;
+        }
+        let dropped = self
+            .previews
+            .write()
+            .unwrap()
+            .invalidate(&params.text_document.uri);
+        if dropped > 0 {
+            log::info!("dropped {dropped} pending previews of a changed document");
+        }
         let mut src = self.current_text.write().unwrap();
         *src = params.content_changes[0].text.clone();
         let mut src = self.indexed_text.write().unwrap();
         *src = nonsense::IndexedText::new(params.content_changes[0].text.to_owned());
+        if let Some(document) = self
+            .documents
+            .write()
+            .unwrap()
+            .get_mut(&params.text_document.uri)
+        {
+            document.text = params.content_changes[0].text.clone();
+        }
     }
     //
     async fn did_save(&self, params: DidSaveTextDocumentParams) {
@@ -260,15 +1187,53 @@ impl LanguageServer for Backend {
             let mut src = self.current_text.write().unwrap();
             *src = new_text.clone();
             let mut src = self.indexed_text.write().unwrap();
-            *src = nonsense::IndexedText::new(new_text);
+            *src = nonsense::IndexedText::new(new_text.clone());
+            if let Some(document) = self
+                .documents
+                .write()
+                .unwrap()
+                .get_mut(&params.text_document.uri)
+            {
+                document.text = new_text;
+            }
+        }
+
+        let config = self.polyglot_config.read().unwrap().review.clone();
+        if config.on_save && !preview::is_preview(&params.text_document.uri) {
+            let Some(job) = self.review_job(params.text_document.uri.clone()) else {
+                log::info!("not reviewing {}, it is not open", params.text_document.uri);
+                return;
+            };
+            let client = self.client.clone();
+            // Saves in quick succession are reviewed once, after the last.
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(config.debounce_ms)).await;
+                if !job.reviewer.is_current(&job.uri, job.generation) {
+                    return;
+                }
+                if let Err(e) = job.run().await {
+                    client
+                        .log_message(MessageType::WARNING, format!("review failed: {e}"))
+                        .await;
+                }
+            });
         }
     }
-    //
-    // async fn did_close(&self, _: DidCloseTextDocumentParams) {
-    //     self.client
-    //         .log_message(MessageType::INFO, "file closed!")
-    //         .await;
-    // }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        self.client
+            .log_message(MessageType::INFO, "file closed!")
+            .await;
+        let uri = params.text_document.uri;
+        // Closing a preview discards its edit, closing a document those on it.
+        let mut previews = self.previews.write().unwrap();
+        if preview::is_preview(&uri) {
+            previews.remove(&uri);
+        } else {
+            previews.invalidate(&uri);
+        }
+        self.documents.write().unwrap().remove(&uri);
+    }
     //
     // async fn completion(&self, _: CompletionParams) -> Result<Option<CompletionResponse>> {
     //     Ok(Some(CompletionResponse::Array(vec![
@@ -0,0 +1,234 @@
+//! Token usage accounting per action, model and day, and daily token budgets.
+//!
+//! Usage is kept in a small JSON file below the config root so that the
+//! language server and the command line tools add up to the same totals.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::{BudgetAction, UsageConfig};
+use crate::llm_handlers::traits::TokenUsage;
+
+/// The tokens spent by one action with one model on one day.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    /// Local date as `YYYY-MM-DD`.
+    pub day: String,
+    pub action: String,
+    pub model: String,
+    pub calls: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl UsageRecord {
+    pub fn total_tokens(&self) -> u64 {
+        self.input_tokens + self.output_tokens
+    }
+}
+
+/// The cost of `record` in dollars, if a price is configured for its model.
+fn cost(config: &UsageConfig, record: &UsageRecord) -> Option<f64> {
+    let price = config.prices.get(&record.model)?;
+    Some(
+        (record.input_tokens as f64 * price.input_per_million
+            + record.output_tokens as f64 * price.output_per_million)
+            / 1_000_000.0,
+    )
+}
+
+/// Usage of the last days together with today's budget.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageSummary {
+    pub records: Vec<UsageRecord>,
+    pub today_tokens: u64,
+    pub daily_token_budget: Option<u64>,
+    /// Estimated cost in dollars of the records with a configured price.
+    pub cost: f64,
+}
+
+/// Today's local date as `YYYY-MM-DD`.
+pub fn today() -> String {
+    chrono::Local::now().format("%Y-%m-%d").to_string()
+}
+
+/// Reads all usage records, an absent store holds none.
+pub fn load(path: &Path) -> anyhow::Result<Vec<UsageRecord>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+fn save(path: &Path, records: &[UsageRecord]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(records)?)?;
+    Ok(())
+}
+
+/// Summarizes the records of the last `days` days, newest first.
+pub fn summarize(path: &Path, config: &UsageConfig, days: u32) -> anyhow::Result<UsageSummary> {
+    let first_day = (chrono::Local::now() - chrono::Duration::days(days.saturating_sub(1).into()))
+        .format("%Y-%m-%d")
+        .to_string();
+    let today = today();
+    let all = load(path)?;
+    let today_tokens = all
+        .iter()
+        .filter(|record| record.day == today)
+        .map(UsageRecord::total_tokens)
+        .sum();
+    let mut records: Vec<UsageRecord> = all
+        .into_iter()
+        .filter(|record| record.day >= first_day)
+        .collect();
+    records.sort_by(|a, b| {
+        b.day
+            .cmp(&a.day)
+            .then_with(|| b.total_tokens().cmp(&a.total_tokens()))
+    });
+    let cost = records
+        .iter()
+        .filter_map(|record| cost(config, record))
+        .fold(0.0, |total, cost| total + cost);
+    Ok(UsageSummary {
+        records,
+        today_tokens,
+        daily_token_budget: config.daily_token_budget,
+        cost,
+    })
+}
+
+/// Records the usage of the model to a file and enforces the daily budget.
+#[derive(Debug)]
+pub struct UsageTracker {
+    path: PathBuf,
+    config: UsageConfig,
+    /// The day a budget warning was last handed out.
+    warned_on: Mutex<Option<String>>,
+    /// Serializes the read-modify-write cycles of this process.
+    write_lock: Mutex<()>,
+}
+
+impl UsageTracker {
+    pub fn new(path: PathBuf, config: UsageConfig) -> Self {
+        UsageTracker {
+            path,
+            config,
+            warned_on: Mutex::new(None),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// Summarizes the recorded usage, see `summarize`.
+    pub fn summary(&self, days: u32) -> anyhow::Result<UsageSummary> {
+        summarize(&self.path, &self.config, days)
+    }
+
+    /// Fails if today's budget is used up and the config asks to refuse
+    /// further requests.
+    pub fn check_budget(&self) -> anyhow::Result<()> {
+        let Some(budget) = self.config.daily_token_budget else {
+            return Ok(());
+        };
+        let used = self.summary(1)?.today_tokens;
+        if used < budget {
+            return Ok(());
+        }
+        match self.config.on_budget_exceeded {
+            BudgetAction::Refuse => {
+                bail!("daily token budget of {budget} is used up ({used} tokens spent today)")
+            }
+            BudgetAction::Warn => {
+                log::warn!("daily token budget of {budget} exceeded, {used} tokens spent today");
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns a warning the first time per day the budget is exceeded.
+    pub fn take_budget_warning(&self) -> Option<String> {
+        let budget = self.config.daily_token_budget?;
+        let used = self.summary(1).ok()?.today_tokens;
+        if used < budget {
+            return None;
+        }
+        let today = today();
+        let mut warned_on = self.warned_on.lock().unwrap();
+        if warned_on.as_deref() == Some(today.as_str()) {
+            return None;
+        }
+        *warned_on = Some(today);
+        Some(format!(
+            "Daily token budget of {budget} exceeded, {used} tokens spent today"
+        ))
+    }
+
+    /// Adds one call of `action` with `model` to today's usage.
+    pub fn record(&self, action: &str, model: &str, usage: Option<TokenUsage>) {
+        let _guard = self.write_lock.lock().unwrap();
+        let result = load(&self.path).and_then(|mut records| {
+            let day = today();
+            let index = match records
+                .iter()
+                .position(|r| r.day == day && r.action == action && r.model == model)
+            {
+                Some(index) => index,
+                None => {
+                    records.push(UsageRecord {
+                        day,
+                        action: action.to_owned(),
+                        model: model.to_owned(),
+                        ..Default::default()
+                    });
+                    records.len() - 1
+                }
+            };
+            let entry = &mut records[index];
+            entry.calls += 1;
+            if let Some(usage) = usage {
+                entry.input_tokens += u64::from(usage.input_tokens);
+                entry.output_tokens += u64::from(usage.output_tokens);
+            }
+            save(&self.path, &records)
+        });
+        if let Err(e) = result {
+            log::warn!("cannot record token usage: {e}");
+        }
+    }
+}
+
+/// Prints the summary as a table.
+pub fn print_summary(summary: &UsageSummary) {
+    println!(
+        "{:<10}  {:<40}  {:<30}  {:>5}  {:>9}  {:>9}",
+        "day", "model", "action", "calls", "input", "output"
+    );
+    for record in summary.records.iter() {
+        println!(
+            "{:<10}  {:<40}  {:<30}  {:>5}  {:>9}  {:>9}",
+            record.day,
+            record.model,
+            record.action,
+            record.calls,
+            record.input_tokens,
+            record.output_tokens
+        );
+    }
+    println!();
+    match summary.daily_token_budget {
+        Some(budget) => println!("today: {} of {budget} tokens", summary.today_tokens),
+        None => println!("today: {} tokens", summary.today_tokens),
+    }
+    if summary.cost > 0.0 {
+        println!("estimated cost: ${:.2}", summary.cost);
+    }
+}
@@ -0,0 +1,5 @@
+class Table:
+    def count(self, rows):
+        n = len(rows)
+        self.n = n
+        return dict(n=n)
@@ -0,0 +1,3 @@
+def count(rows):
+    n = len(rows)
+    return n
@@ -0,0 +1,2 @@
+def greet(name, times):
+    return name * times
@@ -0,0 +1,160 @@
+//! Golden-file tests for the code actions in `config/code_actions`.
+//!
+//! Every file `tests/cases/<action title>/<name>.<line>.<col>.<ext>` is run
+//! through the code action named by its directory, with the cursor at the
+//! 1-based `line:col`. The result is compared against `<file>.want`.
+//!
+//! If `<file>.llm.jsonl` exists the model answers are replayed from it. Fixtures
+//! are written by running `polyglot_ls --record <file>.llm.jsonl apply ...`
+//! against a real model. Otherwise, if `<file>.mock.yaml` exists the prompts
+//! are answered by its mock rules, and else every prompt is answered with "MOCK".
+//!
+//! If the action fails, the result is `ERROR: <message>` so failure paths can be
+//! tested as well.
+//!
+//! Run with `UPDATE_GOLDEN=1` to rewrite the `.want` files instead.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use polyglot_ls::apply::{apply_action, language_from_path, parse_position, unified_diff};
+use polyglot_ls::code_action_providers::{load_providers, ProviderMap};
+use polyglot_ls::llm_handlers::mock::MockLLM;
+use polyglot_ls::llm_handlers::replay::ReplayLLM;
+use polyglot_ls::llm_handlers::traits::Llm;
+use tower_lsp::lsp_types::{Range, Url};
+
+struct GoldenCase {
+    action_name: String,
+    input: PathBuf,
+    want: PathBuf,
+    fixture: PathBuf,
+    mock_rules: PathBuf,
+    cursor: String,
+}
+
+fn manifest_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Finds all cases below `cases_dir`, one directory per action.
+fn discover_cases(cases_dir: &Path) -> Vec<GoldenCase> {
+    let mut cases = Vec::new();
+    for action_dir in fs::read_dir(cases_dir).unwrap().flatten() {
+        let action_dir = action_dir.path();
+        if !action_dir.is_dir() {
+            continue;
+        }
+        let action_name = action_dir
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        for entry in fs::read_dir(&action_dir).unwrap().flatten() {
+            let input = entry.path();
+            let file_name = input.file_name().unwrap().to_string_lossy().to_string();
+            if file_name.ends_with(".want")
+                || file_name.ends_with(".out")
+                || file_name.ends_with(".jsonl")
+                || file_name.ends_with(".mock.yaml")
+            {
+                continue;
+            }
+            // <name>.<line>.<col>.<ext>
+            let parts: Vec<&str> = file_name.rsplitn(4, '.').collect();
+            if parts.len() != 4 {
+                panic!(
+                    "{} does not follow <name>.<line>.<col>.<ext>",
+                    input.display()
+                );
+            }
+            cases.push(GoldenCase {
+                action_name: action_name.clone(),
+                want: input.with_file_name(format!("{file_name}.want")),
+                fixture: input.with_file_name(format!("{file_name}.llm.jsonl")),
+                mock_rules: input.with_file_name(format!("{file_name}.mock.yaml")),
+                cursor: format!("{}:{}", parts[2], parts[1]),
+                input,
+            });
+        }
+    }
+    cases.sort_by(|a, b| a.input.cmp(&b.input));
+    cases
+}
+
+fn load_code_actions(prompt_handler: Llm) -> ProviderMap {
+    let (providers, errors) = load_providers(
+        manifest_dir().join("config").join("code_actions"),
+        Arc::new(prompt_handler),
+    );
+    assert!(errors.is_empty(), "cannot load code actions: {errors:?}");
+    providers
+}
+
+/// Returns providers answering from the fixture or mock rules of `case`, if
+/// it has either.
+fn case_providers(case: &GoldenCase) -> Option<ProviderMap> {
+    if case.fixture.exists() {
+        let replay = ReplayLLM::from_file(&case.fixture).unwrap();
+        return Some(load_code_actions(Llm::Replay(replay)));
+    }
+    if case.mock_rules.exists() {
+        let mock = MockLLM::from_rules_file(&case.mock_rules).unwrap();
+        return Some(load_code_actions(Llm::Mock(mock)));
+    }
+    None
+}
+
+async fn run_case(providers: &ProviderMap, case: &GoldenCase) -> Result<String, String> {
+    let source = fs::read_to_string(&case.input).map_err(|e| e.to_string())?;
+    let lang = language_from_path(&case.input).ok_or("unknown file extension")?;
+    let uri = Url::from_file_path(&case.input).map_err(|_| "invalid path")?;
+    let cursor = parse_position(&case.cursor)?;
+    let result = apply_action(
+        providers,
+        &source,
+        &uri,
+        lang,
+        &case.action_name,
+        &Range::new(cursor, cursor),
+    )
+    .await;
+    Ok(result.unwrap_or_else(|e| format!("ERROR: {e}\n")))
+}
+
+#[tokio::test]
+async fn golden_cases() {
+    let update = std::env::var("UPDATE_GOLDEN").is_ok_and(|v| v == "1");
+    let mock_providers = load_code_actions(Llm::Mock(MockLLM::new("MOCK".to_owned())));
+    let cases = discover_cases(&manifest_dir().join("tests").join("cases"));
+    assert!(!cases.is_empty(), "no golden cases found");
+
+    let mut failures = Vec::new();
+    for case in cases.iter() {
+        let case_providers = case_providers(case);
+        let providers = case_providers.as_ref().unwrap_or(&mock_providers);
+        let got = match run_case(providers, case).await {
+            Ok(got) => got,
+            Err(e) => {
+                failures.push(format!("{}: {e}", case.input.display()));
+                continue;
+            }
+        };
+        if update {
+            fs::write(&case.want, &got).unwrap();
+            continue;
+        }
+        let want = fs::read_to_string(&case.want).unwrap_or_default();
+        if got != want {
+            failures.push(unified_diff(&case.want, &want, &got));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} golden cases failed:\n{}",
+        failures.len(),
+        cases.len(),
+        failures.join("\n")
+    );
+}